@@ -1,12 +1,37 @@
-use colored::Colorize;
+use chrono::{DateTime, Local};
+use colored::{Color, ColoredString, Colorize};
 use crossterm::cursor::MoveTo;
-use crossterm::event::{poll, read, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::event::{
+    read, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
 use crossterm::terminal::{self, Clear, ClearType};
-use std::io::{stdout, ErrorKind, Read, Write};
-use std::net::TcpStream;
+use rustls::ClientConfig;
+use std::collections::HashSet;
+use std::io::{self, stdout, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use std::{env, process, str};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::{env, fs, iter, mem, panic, process, slice, str};
+use tchat::common::{
+    Frame, Kind, ACK, ACKS, DEFAULT_PORT, ECHO, INVALID_TOKEN, PASSWORD_PROMPT, PING, PONG_LINE,
+    TOKEN_PROMPT, USERS,
+};
+use tchat::tls::{self, TlsStream};
+use tchat::{strip_colors, visible_chars, Connection};
+use unicode_width::UnicodeWidthChar;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_SCROLLBACK: usize = 10_000;
+const MAX_HISTORY: usize = 100;
+/// Shown in front of what is being typed, so the prompt stands out from the
+/// chat.
+const DEFAULT_PROMPT_PREFIX: &str = "> ";
 
 struct Rect {
     x: usize,
@@ -15,45 +40,748 @@ struct Rect {
     h: usize,
 }
 
-fn chat_window(buffer: &mut String, chat: &[String], boundary: Rect, offset: usize) {
-    let n = chat.len();
-    let m = n.checked_sub(boundary.h + offset).unwrap_or(0);
+/// A line of the chat history. `own` marks the messages typed here so they
+/// can be told apart from everybody else's.
+struct Entry {
+    text: String,
+    own: bool,
+    /// The connection an own message went out on and its number there,
+    /// until the server acknowledges it.
+    pending: Option<(u64, u64)>,
+}
+
+/// The chat history and how far up it is scrolled.
+struct Chat {
+    entries: Vec<Entry>,
+    /// How many rows the bottom of the view is above the last one.
+    scroll_offset: usize,
+    /// The columns the entries wrap at.
+    width: usize,
+}
+
+impl Chat {
+    /// Adds `entry` at the bottom. Scrolled up, the view stays still; at the
+    /// bottom it follows new entries.
+    fn push(&mut self, entry: Entry) {
+        if self.scroll_offset > 0 {
+            self.scroll_offset += wrap_lines(slice::from_ref(&entry), self.width).len();
+        }
+        self.entries.push(entry);
+    }
+}
+
+impl Entry {
+    fn notice(text: ColoredString) -> Self {
+        Self {
+            text: text.to_string(),
+            own: false,
+            pending: None,
+        }
+    }
+
+    /// Renders a line from the server according to its kind. Untagged lines
+    /// are shown as they are.
+    fn received(line: &str) -> Self {
+        let text = match parse_line(line) {
+            Some((Kind::Message, payload)) => color_author(&payload),
+            Some((Kind::Join, payload)) => format!("*** {payload}").green().to_string(),
+            Some((Kind::Leave, payload)) => format!("*** {payload}").yellow().to_string(),
+            Some((Kind::Roster, payload)) => {
+                let nicks = payload.split_whitespace().collect::<Vec<_>>();
+                format!("Online: {}", nicks.join(", ")).cyan().to_string()
+            }
+            Some((_, payload)) => payload,
+            None => line.to_string(),
+        };
+        Self {
+            text,
+            own: false,
+            pending: None,
+        }
+    }
+}
+
+/// Splits a line from the server into its kind and payload, whether the
+/// server sends tagged lines or JSON frames. Messages in a frame get their
+/// time and author put back in front, like a tagged line has them.
+fn parse_line(line: &str) -> Option<(Kind, String)> {
+    if line.starts_with('{') {
+        let frame = serde_json::from_str::<Frame>(line).ok()?;
+        let text = if frame.kind == Kind::Message {
+            let time = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(frame.ts));
+            let time = time.format("%H:%M:%S");
+            match frame.nick {
+                Some(nick) => format!("[{time}] <{nick}> {}", frame.text),
+                None => format!("[{time}] {}", frame.text),
+            }
+        } else {
+            frame.text
+        };
+        return Some((frame.kind, text));
+    }
+    Kind::parse(line).map(|(kind, payload)| (kind, payload.to_string()))
+}
+
+/// Finds who wrote a message payload, whether it is `<nick> text`, a
+/// `* nick action` or a private `*nick* text`, behind its timestamp.
+fn message_author(payload: &str) -> Option<&str> {
+    let (_, rest) = payload.split_once("] ")?;
+    if let Some(rest) = rest.strip_prefix('<') {
+        rest.split_once("> ").map(|(nick, _)| nick)
+    } else if let Some(rest) = rest.strip_prefix("* ") {
+        rest.split_whitespace().next()
+    } else if let Some(rest) = rest.strip_prefix('*') {
+        rest.split_once("* ").map(|(nick, _)| nick)
+    } else {
+        None
+    }
+}
+
+/// Colors everyone can be told apart by. Green, yellow and cyan are left out
+/// since joins, leaves and the roster use them.
+const NICK_COLORS: [Color; 8] = [
+    Color::Red,
+    Color::Blue,
+    Color::Magenta,
+    Color::BrightRed,
+    Color::BrightGreen,
+    Color::BrightYellow,
+    Color::BrightBlue,
+    Color::BrightMagenta,
+];
+
+/// Picks the color of `nick` from a hash of it, so the same nickname always
+/// gets the same color, across runs too.
+fn nick_color(nick: &str) -> Color {
+    // FNV-1a, which unlike the standard hasher is guaranteed to stay put.
+    let hash = nick.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    NICK_COLORS[(hash % NICK_COLORS.len() as u64) as usize]
+}
+
+/// Colors the `<nick>` of a message payload by who it is.
+fn color_author(payload: &str) -> String {
+    let colored = payload.split_once("] ").and_then(|(time, rest)| {
+        let (nick, text) = rest.strip_prefix('<')?.split_once("> ")?;
+        let author = format!("<{nick}>").color(nick_color(nick));
+        Some(format!("{time}] {author} {text}"))
+    });
+    colored.unwrap_or_else(|| payload.to_string())
+}
+
+/// Writes the chat to `path` without its colors, one entry per line.
+fn save_chat(chat: &[Entry], path: &str) -> io::Result<()> {
+    let mut transcript = String::new();
+    for entry in chat {
+        transcript.push_str(&strip_colors(&entry.text));
+        transcript.push('\n');
+    }
+    fs::write(path, transcript)
+}
+
+/// Handles the commands this client runs itself, which never reach the
+/// server: `/ignore`, `/unignore`, `/save` and `/clear`. Returns whether
+/// `text` was one of them.
+fn local_command(chat: &mut Chat, ignored: &mut HashSet<String>, text: &str) -> bool {
+    let (name, arg) = text.split_once(' ').unwrap_or((text, ""));
+    let arg = arg.trim();
+    let notice = match name {
+        "/ignore" if arg.is_empty() => "Usage: /ignore <nick>".red(),
+        "/ignore" => {
+            ignored.insert(arg.to_string());
+            format!("*** ignoring {arg}").yellow()
+        }
+        "/unignore" if arg.is_empty() => "Usage: /unignore <nick>".red(),
+        "/unignore" if ignored.remove(arg) => format!("*** no longer ignoring {arg}").yellow(),
+        "/unignore" => format!("{arg} is not ignored").red(),
+        "/save" if arg.is_empty() => "Usage: /save <path>".red(),
+        "/save" => match save_chat(&chat.entries, arg) {
+            Ok(()) => format!("*** saved the chat to {arg}").green(),
+            Err(err) => format!("Could not save the chat to {arg}: {err}").red(),
+        },
+        "/clear" => {
+            chat.entries.clear();
+            chat.scroll_offset = 0;
+            return true;
+        }
+        _ => return false,
+    };
+    chat.push(Entry::notice(notice));
+    true
+}
+
+/// Finds where to break `line` so that at most `width` columns show before
+/// the break, if more than that show at all. Wide characters like emoji and
+/// CJK take two columns, and one that doesn't fit goes to the next row. Color
+/// codes take no room on screen, so they aren't counted and never get split.
+fn wrap_point(line: &str, width: usize) -> Option<usize> {
+    let mut shown = 0;
+    for (i, x) in visible_chars(line) {
+        let columns = x.width().unwrap_or(0);
+        // A character wider than a whole row still gets one to itself.
+        if shown + columns > width && shown > 0 {
+            return Some(i);
+        }
+        shown += columns;
+    }
+    None
+}
+
+/// Flattens the chat into screen rows of at most `width` columns,
+/// wrapping long entries instead of cutting them off. Each row keeps the
+/// entry it came from.
+fn wrap_lines(chat: &[Entry], width: usize) -> Vec<(&Entry, &str)> {
+    let mut rows = Vec::new();
+    for entry in chat {
+        for line in entry.text.lines() {
+            let mut rest = line;
+            while let Some(cut) = wrap_point(rest, width.max(1)) {
+                rows.push((entry, &rest[..cut]));
+                rest = &rest[cut..];
+            }
+            rows.push((entry, rest));
+        }
+    }
+    rows
+}
+
+/// How far the chat can scroll up before its first row reaches the top of a
+/// window `height` rows tall.
+fn max_scroll(chat: &[Entry], width: usize, height: usize) -> usize {
+    wrap_lines(chat, width).len().saturating_sub(height)
+}
+
+/// Draws the chat so that its last row sits `offset` rows above the bottom
+/// of `boundary`. An offset of 0 follows the newest messages.
+fn chat_window(buffer: &mut String, chat: &[Entry], boundary: Rect, offset: usize) {
+    let rows = wrap_lines(chat, boundary.w);
+    let n = rows.len();
+    let m = n.saturating_sub(boundary.h + offset);
 
-    for (dy, line) in chat.iter().skip(m + offset).take(boundary.h).enumerate() {
+    for (dy, (entry, row)) in rows.iter().skip(m).take(boundary.h).enumerate() {
         buffer.push_str(&format!(
             "{}{}",
             MoveTo(boundary.x as u16, (boundary.y + dy) as u16),
-            &line[..line.len().min(boundary.w)]
+            if entry.pending.is_some() {
+                row.dimmed().italic()
+            } else if entry.own {
+                row.dimmed()
+            } else {
+                row.normal()
+            }
         ));
     }
 }
 
+/// The part of `prompt` that fits in `room` columns with the cursor, at byte
+/// `cursor`, in view, and the column the cursor is in. Wide characters take
+/// two columns, unless `masked` shows every character as a single `*`.
+fn prompt_view(prompt: &str, cursor: usize, room: usize, masked: bool) -> (Range<usize>, usize) {
+    let columns = |text: &str| -> usize {
+        text.chars()
+            .map(|x| if masked { 1 } else { x.width().unwrap_or(0) })
+            .sum()
+    };
+    // Drop characters off the front until the cursor has a column to itself.
+    let mut start = 0;
+    while start < cursor && columns(&prompt[start..cursor]) + 1 > room {
+        start += prompt[start..].chars().next().map_or(1, char::len_utf8);
+    }
+    let mut end = start;
+    let mut shown = 0;
+    for x in prompt[start..].chars() {
+        shown += columns(x.encode_utf8(&mut [0; 4]));
+        if shown > room {
+            break;
+        }
+        end += x.len_utf8();
+    }
+    (start..end, columns(&prompt[start..cursor]))
+}
+
+/// Takes every complete `\n`-terminated line out of `pending`, leaving a
+/// trailing partial line in place for the next read.
+fn drain_lines(pending: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+        let line = pending.drain(..=pos).collect::<Vec<_>>();
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        lines.push(String::from_utf8_lossy(line).into_owned());
+    }
+    lines
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+impl Status {
+    fn label(self) -> ColoredString {
+        match self {
+            Status::Connected => "connected".green(),
+            Status::Disconnected => "disconnected".red(),
+            Status::Reconnecting => "reconnecting".yellow(),
+        }
+    }
+}
+
+/// What wakes the main loop up: something from the terminal, a line or the
+/// end of connection number `generation` from its reader, or how an attempt
+/// to reconnect went. Every read is also passed on as it came as a `Chunk`,
+/// for the debug mode.
+enum Update {
+    Terminal(Event),
+    Chunk { generation: u64, bytes: Vec<u8> },
+    Line { generation: u64, line: String },
+    Closed { generation: u64, reason: String },
+    Reconnected(io::Result<Arc<dyn Connection>>),
+}
+
+fn connect(
+    ip: &str,
+    port: u16,
+    tls_config: Option<&Arc<ClientConfig>>,
+) -> io::Result<Arc<dyn Connection>> {
+    let address = (ip, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address found"))?;
+    let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+    // Chat lines are small, so don't hold them back to batch them. It only
+    // makes them go out sooner, everything works without it.
+    let _ = tcp.set_nodelay(true);
+    Ok(match tls_config {
+        Some(config) => Arc::new(TlsStream::client(tcp, config.clone(), ip)?),
+        None => Arc::new(tcp),
+    })
+}
+
+/// Reads lines from the server until the connection ends, on a thread of its
+/// own so drawing never waits on the network.
+fn read_lines(stream: Arc<dyn Connection>, generation: u64, updates: Sender<Update>) {
+    let mut buf = [0; 4096];
+    let mut pending = Vec::new();
+    let reason = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break "Server closed the connection".to_string(),
+            Ok(n) => {
+                let bytes = buf[0..n].to_vec();
+                if updates.send(Update::Chunk { generation, bytes }).is_err() {
+                    return;
+                }
+                pending.extend_from_slice(&buf[0..n]);
+                for line in drain_lines(&mut pending) {
+                    if updates.send(Update::Line { generation, line }).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => break format!("Read error: {err}"),
+        }
+    };
+    let _ = updates.send(Update::Closed { generation, reason });
+}
+
+/// The connection to the server and what it takes to bring it back after
+/// it drops.
+struct Link {
+    ip: String,
+    port: u16,
+    tls_config: Option<Arc<ClientConfig>>,
+    stream: Arc<dyn Connection>,
+    /// Counts the connections made so far, so whatever the reader of an
+    /// earlier one still sends can be told apart and ignored.
+    generation: u64,
+    updates: Sender<Update>,
+    status: Status,
+    /// Whether an attempt to reconnect is running on a thread of its own, so
+    /// the UI keeps going while it resolves and connects.
+    connecting: bool,
+    backoff: Duration,
+    retry_at: Instant,
+    /// Answers the server's token prompt. Comes from `--token` or
+    /// `TCHAT_TOKEN`, or else from whatever the user typed at the prompt.
+    token: Option<String>,
+    awaiting_token: bool,
+    /// Whether the next line typed answers the password prompt of a
+    /// registered nickname, so it is masked and kept out of the chat.
+    awaiting_password: bool,
+    /// Whether the server has taken our nickname on this connection. Users
+    /// can get any text into notices from then on, so none of them is taken
+    /// as the token or password prompt anymore.
+    joined: bool,
+    /// Whether the server sends our own messages back, in which case they
+    /// aren't added to the chat when sent.
+    echo: bool,
+    /// Whether the server acknowledges what we send, and how many lines
+    /// it should have counted so far on this connection.
+    acks: bool,
+    sent: u64,
+}
+
+impl Link {
+    fn open(
+        ip: String,
+        port: u16,
+        tls_config: Option<Arc<ClientConfig>>,
+        token: Option<String>,
+        updates: Sender<Update>,
+    ) -> io::Result<Self> {
+        let stream = connect(&ip, port, tls_config.as_ref())?;
+        let link = Self {
+            ip,
+            port,
+            tls_config,
+            stream,
+            generation: 0,
+            updates,
+            status: Status::Connected,
+            connecting: false,
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            token,
+            awaiting_token: false,
+            awaiting_password: false,
+            joined: false,
+            echo: false,
+            acks: false,
+            sent: 0,
+        };
+        link.start_reader();
+        Ok(link)
+    }
+
+    fn start_reader(&self) {
+        let stream = self.stream.clone();
+        let generation = self.generation;
+        let updates = self.updates.clone();
+        thread::spawn(move || read_lines(stream, generation, updates));
+    }
+
+    fn write_line(&mut self, chat: &mut Chat, line: &str) -> bool {
+        match self.stream.write_all(format!("{line}\n").as_bytes()) {
+            Ok(()) => {
+                if line != PONG_LINE {
+                    self.sent += 1;
+                }
+                true
+            }
+            Err(err) => {
+                self.lost(chat, format!("Write error: {err}"));
+                false
+            }
+        }
+    }
+
+    /// Sends `text` to the server as one message and adds it to the chat,
+    /// unless it is the token or a password, which never show up in the
+    /// chat, or the server echoes it.
+    fn send(&mut self, chat: &mut Chat, text: String) {
+        if self.status != Status::Connected {
+            chat.push(Entry::notice("Not connected, message not sent".red()));
+            return;
+        }
+        if self.write_line(chat, &text) {
+            if mem::take(&mut self.awaiting_token) {
+                self.token = Some(text);
+            } else if mem::take(&mut self.awaiting_password) || text.starts_with("/register ") {
+                // Passwords stay out of the chat.
+            } else if !self.echo {
+                chat.push(Entry {
+                    text,
+                    own: true,
+                    pending: self.acks.then_some((self.generation, self.sent)),
+                });
+            }
+        }
+    }
+
+    /// Answers the server's token prompt with the remembered token, if any.
+    fn token_prompt(&mut self, chat: &mut Chat) {
+        match self.token.clone() {
+            Some(token) => {
+                self.write_line(chat, &token);
+            }
+            None => self.awaiting_token = true,
+        }
+    }
+
+    fn lost(&mut self, chat: &mut Chat, reason: String) {
+        if self.status == Status::Connected {
+            self.status = Status::Disconnected;
+            chat.push(Entry::notice(reason.red()));
+            // Ends the reader too, if it is still waiting on the socket.
+            let _ = self.stream.shutdown();
+        }
+    }
+
+    /// Starts another attempt to connect once a dropped connection's
+    /// backoff has run out. The result comes back as an
+    /// [`Update::Reconnected`].
+    fn reconnect(&mut self, chat: &mut Chat) {
+        if self.status == Status::Disconnected {
+            chat.push(Entry::notice("*** disconnected, reconnecting...".yellow()));
+            self.status = Status::Reconnecting;
+            self.backoff = MIN_BACKOFF;
+            self.retry_at = Instant::now();
+        }
+        if self.status != Status::Reconnecting || self.connecting || Instant::now() < self.retry_at
+        {
+            return;
+        }
+        self.connecting = true;
+        let ip = self.ip.clone();
+        let port = self.port;
+        let tls_config = self.tls_config.clone();
+        let updates = self.updates.clone();
+        thread::spawn(move || {
+            let result = connect(&ip, port, tls_config.as_ref());
+            let _ = updates.send(Update::Reconnected(result));
+        });
+    }
+
+    /// Takes over the connection an attempt to reconnect made, or doubles
+    /// the backoff if it failed.
+    fn reconnected(&mut self, chat: &mut Chat, result: io::Result<Arc<dyn Connection>>) {
+        self.connecting = false;
+        match result {
+            Ok(stream) => {
+                self.stream = stream;
+                self.generation += 1;
+                self.start_reader();
+                self.status = Status::Connected;
+                self.awaiting_token = false;
+                self.awaiting_password = false;
+                self.joined = false;
+                self.echo = false;
+                self.acks = false;
+                self.sent = 0;
+                chat.push(Entry::notice("*** reconnected".green()));
+            }
+            Err(_) => {
+                self.retry_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+impl Drop for Link {
+    /// Hangs up however the client exits, so the server sees it leave right
+    /// away instead of waiting for the connection to time out.
+    fn drop(&mut self) {
+        if self.status == Status::Connected {
+            let _ = self.stream.shutdown();
+        }
+    }
+}
+
+/// The lines typed before, recalled with Ctrl-P and Ctrl-N since the arrows
+/// scroll the chat.
+#[derive(Default)]
+struct History {
+    entries: Vec<String>,
+    /// The entry the prompt shows while going through them, and what was
+    /// typed before that started.
+    browsing: Option<(usize, String)>,
+}
+
+impl History {
+    fn push(&mut self, text: &str) {
+        self.browsing = None;
+        if self.entries.last().is_some_and(|last| last == text) {
+            return;
+        }
+        self.entries.push(text.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Steps back to the entry before the one shown, if there is one.
+    fn previous(&mut self, prompt: &str) -> Option<&str> {
+        let index = match &self.browsing {
+            Some((0, _)) => return None,
+            Some((index, _)) => index - 1,
+            None => self.entries.len().checked_sub(1)?,
+        };
+        let draft = self
+            .browsing
+            .take()
+            .map_or_else(|| prompt.to_string(), |(_, draft)| draft);
+        self.browsing = Some((index, draft));
+        Some(&self.entries[index])
+    }
+
+    /// Steps forward again, back to what was being typed past the last
+    /// entry.
+    fn next(&mut self) -> Option<String> {
+        let (index, draft) = self.browsing.take()?;
+        if index + 1 < self.entries.len() {
+            self.browsing = Some((index + 1, draft));
+            Some(self.entries[index + 1].clone())
+        } else {
+            Some(draft)
+        }
+    }
+}
+
+/// Sends what the user typed, unless it is a command the client handles
+/// itself. Returns whether it was `/quit`.
+fn submit(link: &mut Link, chat: &mut Chat, ignored: &mut HashSet<String>, text: String) -> bool {
+    if link.awaiting_token || link.awaiting_password {
+        link.send(chat, text);
+    } else if text.trim() == "/quit" {
+        return true;
+    } else if !local_command(chat, ignored, &text) {
+        link.send(chat, text);
+    }
+    false
+}
+
+fn restore_terminal() {
+    let _ = execute!(stdout(), DisableBracketedPaste);
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(stdout(), Clear(ClearType::All), MoveTo(0, 0));
+}
+
+/// Keeps the terminal in raw mode while alive and restores it when dropped,
+/// so early returns and Ctrl-C don't leave the shell without echo.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        // Without this a paste arrives as plain key presses, and every
+        // newline in it would send whatever is in the prompt so far.
+        execute!(stdout(), EnableBracketedPaste)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // The panic hook already restored the terminal and printed the
+        // message; clearing again would wipe it.
+        if !thread::panicking() {
+            restore_terminal();
+        }
+    }
+}
+
 fn main() {
-    let mut args = env::args();
-    let _program = args.next().expect("program name");
-    let ip = args.next().unwrap_or_else(|| {
-        eprintln!("Usage: <program> <IP>");
+    let mut use_tls = false;
+    let mut insecure = false;
+    let mut token = env::var("TCHAT_TOKEN")
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty());
+    let mut scrollback = DEFAULT_SCROLLBACK;
+    let mut debug = false;
+    let mut prompt_prefix = DEFAULT_PROMPT_PREFIX.to_string();
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tls" => use_tls = true,
+            "--debug" => debug = true,
+            "--insecure" => {
+                use_tls = true;
+                insecure = true;
+            }
+            "--scrollback" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("--scrollback expects a value");
+                    process::exit(1);
+                });
+                scrollback = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|lines| *lines >= 1)
+                    .unwrap_or_else(|| {
+                        eprintln!(
+                            "Invalid --scrollback {value}: expected a whole number of at least 1"
+                        );
+                        process::exit(1);
+                    });
+            }
+            "--prompt-prefix" => {
+                prompt_prefix = args.next().unwrap_or_else(|| {
+                    eprintln!("--prompt-prefix expects a value");
+                    process::exit(1);
+                });
+            }
+            "--token" => {
+                token = Some(args.next().unwrap_or_else(|| {
+                    eprintln!("--token expects a value");
+                    process::exit(1);
+                }));
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let ip = positional.next().unwrap_or_else(|| {
+        eprintln!(
+            "Usage: <program> [--tls] [--insecure] [--token <TOKEN>] [--scrollback <LINES>] [--prompt-prefix <TEXT>] [--debug] <IP> [PORT]"
+        );
         process::exit(1);
     });
+    let port = match positional.next() {
+        Some(port) => port.parse::<u16>().unwrap_or_else(|err| {
+            eprintln!("Invalid port {port}: {err}");
+            process::exit(1);
+        }),
+        None => DEFAULT_PORT,
+    };
 
-    let mut stream = TcpStream::connect(format!("{ip}:6969")).unwrap_or_else(|e| {
-        eprintln!("Failed to connect: {}", e);
-        process::exit(1);
-    });
-    stream.set_nonblocking(true).unwrap();
+    let address = format!("{ip}:{port}");
+    let tls_config = use_tls.then(|| tls::client_config(insecure));
+    let (updates_sender, updates) = channel();
+    let mut link =
+        Link::open(ip, port, tls_config, token, updates_sender.clone()).unwrap_or_else(|e| {
+            eprintln!("Failed to connect: {}", e);
+            process::exit(1);
+        });
 
     let (mut w, mut h) = terminal::size().unwrap_or((80, 24));
 
-    terminal::enable_raw_mode().unwrap();
+    let raw_mode = RawMode::enable().unwrap();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
     let mut stdout = stdout();
 
+    thread::spawn(move || {
+        while let Ok(event) = read() {
+            if updates_sender.send(Update::Terminal(event)).is_err() {
+                break;
+            }
+        }
+    });
+
     let bar_char = "█".on_white();
     let mut bar = bar_char.repeat(w as usize);
     let mut quit = false;
+    let mut confirm_quit = false;
     let mut prompt = String::new();
-    let mut chat = Vec::new();
-    let mut buf = [0; 64];
-    let mut scroll_offset = 0;
+    let mut cursor = 0;
+    let mut chat = Chat {
+        entries: Vec::new(),
+        scroll_offset: 0,
+        width: w as usize,
+    };
+    let mut users = None;
+    let mut ignored = HashSet::new();
+    let mut history = History::default();
 
     let quit_msg = "Exiting program. Goodbye!".bright_blue().bold();
     let title = "Tchat";
@@ -62,85 +790,271 @@ fn main() {
     let mut last_buffer = String::new();
 
     while !quit {
-        while poll(Duration::ZERO).unwrap() {
-            match read().unwrap() {
-                Event::Key(event) => match event.kind {
-                    KeyEventKind::Press => match event.code {
+        // Sleep until there is something to show, or until it's time to
+        // try connecting again, then take everything that's waiting.
+        // An attempt to reconnect wakes the loop up once it's done.
+        let first = if link.status == Status::Connected || link.connecting {
+            updates.recv().ok()
+        } else {
+            updates
+                .recv_timeout(link.retry_at.saturating_duration_since(Instant::now()))
+                .ok()
+        };
+        for update in first
+            .into_iter()
+            .chain(iter::from_fn(|| updates.try_recv().ok()))
+        {
+            match update {
+                Update::Terminal(Event::Key(event)) if event.kind == KeyEventKind::Press => {
+                    // Ctrl-C only quits when pressed twice in a row, so a
+                    // stray one doesn't end the session.
+                    let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+                    let ctrl_c = ctrl && event.code == KeyCode::Char('c');
+                    if !ctrl_c {
+                        confirm_quit = false;
+                    }
+                    match event.code {
+                        KeyCode::Char('p') if ctrl => {
+                            if let Some(entry) = history.previous(&prompt) {
+                                prompt = entry.to_string();
+                                cursor = prompt.len();
+                            }
+                        }
+                        KeyCode::Char('n') if ctrl => {
+                            if let Some(entry) = history.next() {
+                                prompt = entry;
+                                cursor = prompt.len();
+                            }
+                        }
+                        // Ctrl-W takes the word before the cursor, with any
+                        // spaces after it, like readline, and Ctrl-U the
+                        // whole line.
+                        KeyCode::Char('w') if ctrl => {
+                            let start = prompt[..cursor]
+                                .trim_end()
+                                .trim_end_matches(|x: char| !x.is_whitespace())
+                                .len();
+                            prompt.drain(start..cursor);
+                            cursor = start;
+                        }
+                        KeyCode::Char('u') if ctrl => {
+                            prompt.clear();
+                            cursor = 0;
+                        }
                         KeyCode::Char(x) => {
-                            if x == 'c' && event.modifiers.contains(KeyModifiers::CONTROL) {
-                                chat.push(format!("{}", quit_msg));
+                            if ctrl_c && confirm_quit {
+                                chat.push(Entry::notice(quit_msg.clone()));
                                 quit = true;
+                            } else if ctrl_c {
+                                chat.push(Entry::notice("Press Ctrl-C again to quit".yellow()));
+                                confirm_quit = true;
+                                chat.scroll_offset = 0;
                             } else {
-                                prompt.push(x);
+                                prompt.insert(cursor, x);
+                                cursor += x.len_utf8();
                             }
                         }
-                        KeyCode::Enter => {
-                            if !prompt.is_empty() {
-                                let message = prompt.clone() + "\n";
-                                stream.write_all(message.as_bytes()).unwrap();
-                                chat.push(prompt.clone());
-                                prompt.clear();
+                        KeyCode::Enter if !prompt.is_empty() => {
+                            // Tokens and passwords are never kept.
+                            if !link.awaiting_token
+                                && !link.awaiting_password
+                                && !prompt.starts_with("/register ")
+                            {
+                                history.push(&prompt);
                             }
+                            history.browsing = None;
+                            if submit(&mut link, &mut chat, &mut ignored, mem::take(&mut prompt)) {
+                                chat.push(Entry::notice(quit_msg.clone()));
+                                quit = true;
+                            }
+                            cursor = 0;
+                            chat.scroll_offset = 0;
                         }
                         KeyCode::Backspace => {
-                            prompt.pop();
+                            if let Some((i, _)) = prompt[..cursor].char_indices().next_back() {
+                                prompt.remove(i);
+                                cursor = i;
+                            }
                         }
-                        KeyCode::Up => {
-                            if scroll_offset < chat.len() {
-                                scroll_offset += 1;
+                        KeyCode::Left => {
+                            if let Some((i, _)) = prompt[..cursor].char_indices().next_back() {
+                                cursor = i;
                             }
                         }
-                        KeyCode::Down => {
-                            if scroll_offset > 0 {
-                                scroll_offset -= 1;
+                        KeyCode::Right => {
+                            if let Some(x) = prompt[cursor..].chars().next() {
+                                cursor += x.len_utf8();
                             }
                         }
+                        KeyCode::Home => {
+                            cursor = 0;
+                        }
+                        KeyCode::End => {
+                            cursor = prompt.len();
+                        }
+                        KeyCode::Up
+                            if chat.scroll_offset
+                                < max_scroll(&chat.entries, w as usize, h as usize - 3) =>
+                        {
+                            chat.scroll_offset += 1;
+                        }
+                        KeyCode::Down if chat.scroll_offset > 0 => {
+                            chat.scroll_offset -= 1;
+                        }
+                        KeyCode::PageUp => {
+                            let page = h as usize - 3;
+                            chat.scroll_offset = (chat.scroll_offset + page).min(max_scroll(
+                                &chat.entries,
+                                w as usize,
+                                page,
+                            ));
+                        }
+                        KeyCode::PageDown => {
+                            chat.scroll_offset = chat.scroll_offset.saturating_sub(h as usize - 3);
+                        }
+                        KeyCode::Esc => {
+                            chat.scroll_offset = 0;
+                        }
+                        KeyCode::F(12) => {
+                            debug = !debug;
+                            chat.push(Entry::notice(if debug {
+                                "Debug mode on, showing every read from the server".yellow()
+                            } else {
+                                "Debug mode off".yellow()
+                            }));
+                        }
                         _ => {}
-                    },
-                    _ => {}
-                },
-                Event::Paste(data) => {
-                    prompt.push_str(&data);
+                    }
+                }
+                Update::Terminal(Event::Paste(data)) => {
+                    // Every complete pasted line goes out as its own message;
+                    // whatever follows the last newline stays in the prompt.
+                    let data = data.replace('\r', "");
+                    let tail = prompt.split_off(cursor);
+                    let mut lines = data.split('\n');
+                    prompt.push_str(lines.next().unwrap_or_default());
+                    for line in lines {
+                        let text = mem::replace(&mut prompt, line.to_string());
+                        if !text.is_empty() {
+                            if submit(&mut link, &mut chat, &mut ignored, text) {
+                                chat.push(Entry::notice(quit_msg.clone()));
+                                quit = true;
+                                break;
+                            }
+                            chat.scroll_offset = 0;
+                        }
+                    }
+                    cursor = prompt.len();
+                    prompt.push_str(&tail);
                 }
-                Event::Resize(nw, nh) => {
+                Update::Terminal(Event::Resize(nw, nh)) => {
                     w = nw;
                     h = nh;
+                    chat.width = w as usize;
                     bar = bar_char.repeat(w as usize);
                 }
-                _ => {}
-            }
-        }
-
-        match stream.read(&mut buf) {
-            Ok(n) => {
-                if n > 0 {
-                    chat.push(str::from_utf8(&buf[0..n]).unwrap().to_string());
+                Update::Terminal(_) => {}
+                Update::Line { generation, line } if generation == link.generation => {
+                    match parse_line(&line) {
+                        Some((Kind::Control, payload)) => {
+                            if payload == PING {
+                                link.write_line(&mut chat, PONG_LINE);
+                            } else if payload == ECHO {
+                                link.echo = true;
+                            } else if payload == ACKS {
+                                link.acks = true;
+                            } else if let Some(count) = payload.strip_prefix(ACK) {
+                                if let Ok(count) = count.parse::<u64>() {
+                                    for entry in &mut chat.entries {
+                                        if entry.pending.is_some_and(|(generation, sent)| {
+                                            generation == link.generation && sent <= count
+                                        }) {
+                                            entry.pending = None;
+                                        }
+                                    }
+                                }
+                            } else if let Some(count) = payload.strip_prefix(USERS) {
+                                // Only sent once our nickname is taken.
+                                link.joined = true;
+                                users = count.parse::<usize>().ok();
+                            }
+                            continue;
+                        }
+                        Some((Kind::System, payload))
+                            if !link.joined && strip_colors(&payload) == PASSWORD_PROMPT =>
+                        {
+                            link.awaiting_password = true;
+                        }
+                        Some((Kind::System, payload))
+                            if !link.joined && strip_colors(&payload) == TOKEN_PROMPT =>
+                        {
+                            link.token_prompt(&mut chat);
+                        }
+                        Some((Kind::System, payload))
+                            if !link.joined
+                                && strip_colors(&payload).starts_with(INVALID_TOKEN) =>
+                        {
+                            link.token = None;
+                        }
+                        Some((Kind::Message, payload))
+                            if message_author(&payload)
+                                .is_some_and(|nick| ignored.contains(nick)) =>
+                        {
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    chat.push(Entry::received(&line));
                 }
-            }
-            Err(err) => {
-                if err.kind() != ErrorKind::WouldBlock {
-                    eprintln!("Read error: {}", err);
-                    process::exit(1);
+                Update::Closed { generation, reason } if generation == link.generation => {
+                    link.lost(&mut chat, reason);
+                }
+                Update::Chunk { generation, bytes } if generation == link.generation => {
+                    if debug {
+                        chat.push(Entry::notice(
+                            format!("<< {} bytes: {}", bytes.len(), bytes.escape_ascii())
+                                .bright_black(),
+                        ));
+                    }
                 }
+                Update::Reconnected(result) => link.reconnected(&mut chat, result),
+                Update::Chunk { .. } | Update::Line { .. } | Update::Closed { .. } => {}
             }
-        };
+        }
+        // Forget the oldest entries past the scrollback. The view stays put,
+        // since it is measured from the bottom, unless it showed them.
+        if chat.entries.len() > scrollback {
+            chat.entries.drain(..chat.entries.len() - scrollback);
+            chat.scroll_offset =
+                chat.scroll_offset
+                    .min(max_scroll(&chat.entries, w as usize, h as usize - 3));
+        }
+
+        if link.status != Status::Connected {
+            users = None;
+            link.reconnect(&mut chat);
+        }
 
         let mut buffer = String::new();
         buffer.push_str(&Clear(ClearType::All).to_string());
 
         chat_window(
             &mut buffer,
-            &chat,
+            &chat.entries,
             Rect {
                 x: 0,
                 y: 1,
                 w: w as usize,
                 h: h as usize - 3,
             },
-            scroll_offset,
+            chat.scroll_offset,
         );
 
-        // Draw the top bar with title
+        // Draw the top bar with title and how many users are online
+        let title = match users {
+            Some(users) => format!("{title} - {users} online"),
+            None => title.to_string(),
+        };
         buffer.push_str(&format!(
             "{}{}{}{}",
             MoveTo(0, 0),
@@ -149,24 +1063,114 @@ fn main() {
             title.black().on_white()
         ));
 
-        // Draw the bar at the bottom
-        buffer.push_str(&format!("{}{}", MoveTo(0, h - 2), bar));
+        // Draw the bar at the bottom with the server address and status
+        buffer.push_str(&format!(
+            "{}{}{}{}{}",
+            MoveTo(0, h - 2),
+            bar,
+            MoveTo(1, h - 2),
+            format!("{address} ").black().on_white(),
+            link.status.label().on_white()
+        ));
 
-        // Draw the prompt
+        // Draw the prompt, masked while it holds the token or a password.
+        // When it doesn't fit it scrolls sideways to keep the cursor in view.
+        let prefix_width = prompt_prefix
+            .chars()
+            .map(|x| x.width().unwrap_or(0))
+            .sum::<usize>();
+        let room = (w as usize).saturating_sub(prefix_width).max(1);
+        let masked = link.awaiting_token || link.awaiting_password;
+        let (shown, cursor_column) = prompt_view(&prompt, cursor, room, masked);
+        let shown = &prompt[shown];
+        let visible = if masked {
+            "*".repeat(shown.chars().count())
+        } else {
+            shown.to_string()
+        };
         buffer.push_str(&format!(
-            "{}{}",
+            "{}{}{}",
             MoveTo(0, h - 1),
-            &prompt[..prompt.len().min(w as usize)]
+            prompt_prefix.bold(),
+            visible
         ));
 
+        // Place the terminal cursor at the insertion point
+        let cursor_x = (prefix_width + cursor_column).min(w as usize - 1);
+        buffer.push_str(&format!("{}", MoveTo(cursor_x as u16, h - 1)));
+
         if buffer != last_buffer {
             stdout.write_all(buffer.as_bytes()).unwrap();
             stdout.flush().unwrap();
             last_buffer = buffer;
         }
+    }
 
-        thread::sleep(Duration::from_millis(33));
+    drop(link);
+    drop(raw_mode);
+    println!("{}", quit_msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_line_split_inside_a_character_comes_out_whole() {
+        let line = "привет 👋\n".as_bytes();
+        // In the middle of the emoji's four bytes.
+        let split = line.len() - 3;
+        let mut pending = line[..split].to_vec();
+        assert!(drain_lines(&mut pending).is_empty());
+        pending.extend_from_slice(&line[split..]);
+        assert_eq!(drain_lines(&mut pending), ["привет 👋"]);
+        assert!(pending.is_empty());
     }
 
-    terminal::disable_raw_mode().unwrap();
+    #[test]
+    fn rows_never_take_more_columns_than_the_width() {
+        let chat = [
+            Entry::notice("a".repeat(25).normal()),
+            // The fifth emoji would take columns 10 and 11.
+            Entry::notice("a👋👋👋👋👋 你好".normal()),
+        ];
+        let rows = wrap_lines(&chat, 10)
+            .into_iter()
+            .map(|(_, row)| row)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                "a".repeat(10),
+                "a".repeat(10),
+                "a".repeat(5),
+                "a👋👋👋👋".to_string(),
+                "👋 你好".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn the_prompt_scrolls_by_columns() {
+        let prompt = "你好你好";
+        // Each character takes two of the five columns, and the cursor one.
+        assert_eq!(prompt_view(prompt, prompt.len(), 5, false), (6..12, 4));
+        assert_eq!(prompt_view(prompt, 0, 5, false), (0..6, 0));
+        // Stars take one column each.
+        assert_eq!(prompt_view(prompt, prompt.len(), 5, true), (0..12, 4));
+    }
+
+    #[test]
+    fn notices_keep_a_scrolled_up_view_still() {
+        let mut chat = Chat {
+            entries: Vec::new(),
+            scroll_offset: 2,
+            width: 10,
+        };
+        chat.push(Entry::notice("a".repeat(15).normal()));
+        assert_eq!(chat.scroll_offset, 4);
+        chat.scroll_offset = 0;
+        chat.push(Entry::notice("a".normal()));
+        assert_eq!(chat.scroll_offset, 0);
+    }
 }
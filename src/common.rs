@@ -0,0 +1,114 @@
+//! The parts of the line protocol both the server and the client need to
+//! agree on.
+//!
+//! Every line the server sends starts with a one letter [`Kind`] tag and a
+//! space, followed by the payload, e.g. `M [12:00:00] <alice> hi` or
+//! `J alice joined`. Lines from the client carry no tag.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const DEFAULT_PORT: u16 = 6969;
+
+/// What a line from the server carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A chat message, sent to a channel or privately.
+    Message,
+    /// A notice from the server itself: prompts, errors and replies to
+    /// commands.
+    System,
+    /// Someone joined the server or a channel. The payload starts with
+    /// their nickname.
+    Join,
+    /// Someone left the server or a channel, or got kicked or banned. The
+    /// payload starts with their nickname.
+    Leave,
+    /// The nicknames of everyone online, separated by spaces.
+    Roster,
+    /// Not meant to be shown, like [`PING`] and [`USERS`].
+    Control,
+}
+
+impl Kind {
+    pub fn tag(self) -> char {
+        match self {
+            Kind::Message => 'M',
+            Kind::System => 'S',
+            Kind::Join => 'J',
+            Kind::Leave => 'L',
+            Kind::Roster => 'R',
+            Kind::Control => 'C',
+        }
+    }
+
+    pub fn from_tag(tag: char) -> Option<Self> {
+        match tag {
+            'M' => Some(Kind::Message),
+            'S' => Some(Kind::System),
+            'J' => Some(Kind::Join),
+            'L' => Some(Kind::Leave),
+            'R' => Some(Kind::Roster),
+            'C' => Some(Kind::Control),
+            _ => None,
+        }
+    }
+
+    /// Splits a line from the server into its kind and payload, or `None`
+    /// if it isn't tagged.
+    pub fn parse(line: &str) -> Option<(Self, &str)> {
+        let mut chars = line.chars();
+        let kind = Self::from_tag(chars.next()?)?;
+        let payload = chars.as_str().strip_prefix(' ').or_else(|| {
+            // A bare tag, e.g. an empty roster.
+            chars.as_str().is_empty().then_some("")
+        })?;
+        Some((kind, payload))
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.tag())
+    }
+}
+
+/// A line from a server started with `--json`, e.g.
+/// `{"nick":"alice","text":"hi","ts":1700000000,"kind":"message"}`. Only
+/// plain messages have a `nick`, and `ts` is in seconds since the Unix epoch.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame {
+    #[serde(default)]
+    pub nick: Option<String>,
+    pub text: String,
+    pub ts: u64,
+    pub kind: Kind,
+}
+
+/// Keepalive sent by the server as a [`Kind::Control`] line; the client
+/// answers with [`PONG_LINE`].
+pub const PING: &str = "ping";
+pub const PONG_LINE: &str = "/pong";
+/// [`Kind::Control`] payload sent before the token prompt by a server started
+/// with `--echo`. Such a server sends clients their own messages back, so the
+/// client shouldn't show them itself.
+pub const ECHO: &str = "echo";
+/// [`Kind::Control`] payload followed by how many users are online, sent to
+/// everyone whenever somebody joins or leaves.
+pub const USERS: &str = "users ";
+/// [`Kind::Control`] payload sent before the token prompt by a server started
+/// with `--acks`. Such a server answers every line but [`PONG_LINE`] with an
+/// [`ACK`].
+pub const ACKS: &str = "acks";
+/// [`Kind::Control`] payload followed by how many lines the server has
+/// received from this client so far, not counting [`PONG_LINE`]s.
+pub const ACK: &str = "ack ";
+
+/// The first line the server sends, which the client answers with the token.
+pub const TOKEN_PROMPT: &str = "Please enter the Token:";
+/// Sent after a registered nickname is picked, answered with its password.
+pub const PASSWORD_PROMPT: &str = "Please enter the password for this nickname:";
+/// Start of the line sent right before a client with a wrong token is
+/// disconnected.
+pub const INVALID_TOKEN: &str = "Invalid token!";
@@ -0,0 +1,2627 @@
+// Errors are reported with `print_error` where they happen, so callers only
+// need to know that something failed.
+#![allow(clippy::result_unit_err)]
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, Salt, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Local, Utc};
+use colored::{ColoredString, Colorize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Write as OtherWrite},
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    iter,
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    result, str,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub mod common;
+pub mod metrics;
+pub mod tls;
+
+pub use common::DEFAULT_PORT;
+use common::{
+    Frame, Kind, ACK, ACKS, ECHO, INVALID_TOKEN, PASSWORD_PROMPT, PING, PONG_LINE, TOKEN_PROMPT,
+    USERS,
+};
+use metrics::{Metered, Metrics};
+
+pub type Result<T> = result::Result<T, ()>;
+
+const DEFAULT_SAFE_MODE: bool = false;
+pub const DEFAULT_BAN_LIMIT: Duration = Duration::from_secs(10 * 60);
+pub const DEFAULT_MESSAGE_RATE: Duration = Duration::from_secs(1);
+pub const DEFAULT_MESSAGE_BURST: usize = 1;
+pub const DEFAULT_STRIKE_LIMIT: i32 = 10;
+pub const DEFAULT_MAX_CONNS_PER_IP: usize = 4;
+pub const DEFAULT_MAX_CLIENTS: usize = 256;
+const DEFAULT_MAX_CHANNELS: usize = 64;
+const MAX_NICK_LEN: usize = 32;
+const MAX_CHANNEL_LEN: usize = 32;
+const DEFAULT_CHANNEL: &str = "#general";
+const BANS_PATH: &str = "bans.txt";
+const DEFAULT_MOTD: &str = "Type /help to see the commands";
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(90);
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+const MAX_QUEUED_MESSAGES: usize = 5;
+pub const DEFAULT_READ_BUFFER: usize = 8 * 1024;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+pub const DEFAULT_AUTH_ATTEMPTS: usize = 5;
+const DEFAULT_AUTH_WINDOW: Duration = Duration::from_secs(5 * 60);
+pub const DEFAULT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_LEVEL: Level = Level::Info;
+
+pub static SAFE_MODE: AtomicBool = AtomicBool::new(DEFAULT_SAFE_MODE);
+
+pub struct Sens<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Sens<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(inner) = self;
+        if SAFE_MODE.load(Ordering::Relaxed) {
+            write!(f, "[REDACTED]")
+        } else {
+            inner.fmt(f)
+        }
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_bytes: u64,
+}
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+fn open_log(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Also appends everything that gets logged to
+/// `path`, moving it to `<path>.1` once it would grow past `max_bytes`.
+pub fn set_log_file(path: &Path, max_bytes: u64) -> Result<()> {
+    let file = open_log(path).map_err(|err| {
+        print_error(format!("could not open log file {}: {err}", path.display()));
+    })?;
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+    *LOG_FILE.lock().unwrap() = Some(LogFile {
+        path: path.to_path_buf(),
+        file,
+        size,
+        max_bytes,
+    });
+    Ok(())
+}
+
+fn log_to_file(level: &str, message: &dyn fmt::Display) {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+    let Some(log) = log_file.as_mut() else {
+        return;
+    };
+    let line = format!(
+        "{} {level}: {message}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S")
+    );
+
+    if log.size > 0 && log.size + line.len() as u64 > log.max_bytes {
+        let mut rotated = log.path.clone().into_os_string();
+        rotated.push(".1");
+        // Logging can't report its own failures through print_error.
+        let reopened = fs::rename(&log.path, &rotated).and_then(|()| open_log(&log.path));
+        match reopened {
+            Ok(file) => {
+                log.file = file;
+                log.size = 0;
+            }
+            Err(err) => eprintln!("could not rotate log file {}: {err}", log.path.display()),
+        }
+    }
+
+    match log.file.write_all(line.as_bytes()) {
+        Ok(()) => log.size += line.len() as u64,
+        Err(err) => eprintln!("could not write log file {}: {err}", log.path.display()),
+    }
+}
+
+/// How much the server logs, from only errors up to every keepalive.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    fn label(self) -> ColoredString {
+        let name = self.name().bold();
+        match self {
+            Level::Error => name.red(),
+            Level::Warn => name.yellow(),
+            Level::Info => name.truecolor(99, 105, 132),
+            Level::Debug => name.blue(),
+            Level::Trace => name.dimmed(),
+        }
+    }
+
+    /// The next level up, used for every `-v` on the command line.
+    pub fn more_verbose(self) -> Self {
+        match self {
+            Level::Error => Level::Warn,
+            Level::Warn => Level::Info,
+            Level::Info => Level::Debug,
+            Level::Debug | Level::Trace => Level::Trace,
+        }
+    }
+}
+
+/// Messages above this level are dropped. Holds a `Level` as `u8`.
+pub static LOG_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LOG_LEVEL as u8);
+
+fn log<T: fmt::Display>(level: Level, message: T) {
+    if level as u8 > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    log_to_file(level.name(), &message);
+    if level <= Level::Warn {
+        eprintln!("{}: {}", level.label(), message);
+    } else {
+        println!("{}: {}", level.label(), message);
+    }
+}
+
+pub fn print_error<T: fmt::Display>(message: T) {
+    log(Level::Error, message);
+}
+
+pub fn print_warn<T: fmt::Display>(message: T) {
+    log(Level::Warn, message);
+}
+
+pub fn print_info<T: fmt::Display>(message: T) {
+    log(Level::Info, message);
+}
+
+pub fn print_debug<T: fmt::Display>(message: T) {
+    log(Level::Debug, message);
+}
+
+pub fn print_trace<T: fmt::Display>(message: T) {
+    log(Level::Trace, message);
+}
+
+/// Writes one line to a client, logging what couldn't be sent and to whom.
+/// Every line sent to a client goes through here, and through the
+/// [`Metered`] connection it is wrapped in, which counts its bytes.
+fn send_line(
+    mut conn: &dyn Connection,
+    addr: SocketAddr,
+    line: impl fmt::Display,
+    what: &str,
+) -> io::Result<()> {
+    writeln!(conn, "{line}").map_err(|err| {
+        print_error(format!(
+            "could not send {what} to {}: {}",
+            Sens(addr),
+            Sens(&err)
+        ));
+        err
+    })
+}
+
+/// The server's end of a client connection. Implemented for `TcpStream` and
+/// `tls::TlsStream`; anything else (e.g. an in-memory stream in tests) can
+/// stand in for it.
+pub trait Connection: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, bytes)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Write for &dyn Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Connection::write_all(*self, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+pub enum Message {
+    /// The server answers on `accepted` with the connection to read the
+    /// client from, if it took the client.
+    ClientConnected {
+        author: Arc<dyn Connection>,
+        author_addr: SocketAddr,
+        accepted: Sender<Option<Arc<dyn Connection>>>,
+    },
+    ClientDisconnected {
+        author_addr: SocketAddr,
+    },
+    NewMessage {
+        author_addr: SocketAddr,
+        bytes: Vec<u8>,
+    },
+    /// The client went past [`MAX_MESSAGE_LEN`] without ending the line, so
+    /// its reader gave up on it.
+    MessageTooLong {
+        author_addr: SocketAddr,
+    },
+    Shutdown,
+    Tick,
+}
+
+struct Client {
+    conn: Arc<dyn Connection>,
+    last_message: SystemTime,
+    last_seen: SystemTime,
+    /// When the client connected, for `/whois` and the session length logged
+    /// once it leaves.
+    connected_at: SystemTime,
+    /// Messages the client may still send right away, one comes back every
+    /// `message_rate` up to `message_burst`.
+    allowance: usize,
+    refilled_at: SystemTime,
+    /// Messages that came in faster than the rate limit allows, oldest first.
+    queued: VecDeque<String>,
+    strike_count: i32,
+    /// Lines received so far, for `--acks`.
+    received: u64,
+    /// Bytes read from the client so far, counted by its connection.
+    bytes_in: Arc<AtomicU64>,
+    /// Bytes written to the client so far, counted by its connection.
+    bytes_out: Arc<AtomicU64>,
+    authed: bool,
+    admin: bool,
+    nick: String,
+    /// A registered nickname the client asked for, until it gives the
+    /// password.
+    claiming: Option<String>,
+    /// Sent back to whoever privately messages the client, until it is back.
+    away: Option<String>,
+    channel: String,
+}
+
+impl Client {
+    /// Refills the client's allowance for the time since the last refill and
+    /// takes a message out of it, if there is one left.
+    fn take_allowance(&mut self, now: SystemTime, rate: Duration, burst: usize) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).unwrap_or_else(|err| {
+            print_error(format!(
+                "message rate check on new message: the clock might have gone backwards: {err}"
+            ));
+            Duration::from_secs(0)
+        });
+        // No rate at all refills everything, and more refills than the burst
+        // holds change nothing.
+        let refills = elapsed
+            .as_nanos()
+            .checked_div(rate.as_nanos())
+            .map_or(burst, |refills| refills.min(burst as u128) as usize);
+        if self.allowance + refills >= burst {
+            self.allowance = burst;
+            self.refilled_at = now;
+        } else {
+            self.allowance += refills;
+            self.refilled_at += rate * refills as u32;
+        }
+        if self.allowance > 0 {
+            self.allowance -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+type Clients = HashMap<SocketAddr, Client>;
+
+/// A slash command. `usage` and `about` make up its line in `/help`, which
+/// only shows `admin` commands to admins.
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    about: &'static str,
+    admin: bool,
+    run: fn(server: &mut Server, author_addr: SocketAddr, args: &str),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help",
+        about: "show this list",
+        admin: false,
+        run: help_command,
+    },
+    Command {
+        name: "list",
+        usage: "/list",
+        about: "show who is online",
+        admin: false,
+        run: list_command,
+    },
+    Command {
+        name: "msg",
+        usage: "/msg <nick> <text>",
+        about: "send a private message",
+        admin: false,
+        run: msg_command,
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        about: "describe what you are doing",
+        admin: false,
+        run: me_command,
+    },
+    Command {
+        name: "away",
+        usage: "/away [message]",
+        about: "mark yourself away, or back without a message",
+        admin: false,
+        run: away_command,
+    },
+    Command {
+        name: "join",
+        usage: "/join #channel",
+        about: "switch to another channel",
+        admin: false,
+        run: join_command,
+    },
+    Command {
+        name: "part",
+        usage: "/part",
+        about: "go back to #general",
+        admin: false,
+        run: part_command,
+    },
+    Command {
+        name: "topic",
+        usage: "/topic [text]",
+        about: "show or set the channel's topic",
+        admin: false,
+        run: topic_command,
+    },
+    Command {
+        name: "nick",
+        usage: "/nick <name>",
+        about: "change your nickname",
+        admin: false,
+        run: nick_command,
+    },
+    Command {
+        name: "register",
+        usage: "/register <password>",
+        about: "claim your nickname, asking for the password from then on",
+        admin: false,
+        run: register_command,
+    },
+    Command {
+        name: "stats",
+        usage: "/stats",
+        about: "show how the server is doing",
+        admin: true,
+        run: stats_command,
+    },
+    Command {
+        name: "whois",
+        usage: "/whois <nick>",
+        about: "show where a user connected from and when",
+        admin: true,
+        run: whois_command,
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <nick>",
+        about: "disconnect a user",
+        admin: true,
+        run: kick_command,
+    },
+    Command {
+        name: "ban",
+        usage: "/ban <nick> [minutes]",
+        about: "ban a user's address",
+        admin: true,
+        run: ban_command,
+    },
+    Command {
+        name: "bans",
+        usage: "/bans",
+        about: "show who is banned and for how long",
+        admin: true,
+        run: bans_command,
+    },
+    Command {
+        name: "unban",
+        usage: "/unban <ip>",
+        about: "lift a ban",
+        admin: true,
+        run: unban_command,
+    },
+];
+
+fn help_command(server: &mut Server, author_addr: SocketAddr, _args: &str) {
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let commands = COMMANDS
+        .iter()
+        .filter(|command| author.admin || !command.admin)
+        .collect::<Vec<_>>();
+    let width = commands
+        .iter()
+        .map(|command| command.usage.len())
+        .max()
+        .unwrap_or_default();
+    let now = server.clock.now();
+    let help = commands
+        .iter()
+        .map(|command| format!("{:width$}  {}", command.usage, command.about));
+    for line in iter::once("Commands:".to_string()).chain(help) {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server.config.line(Kind::System, None, line, now),
+            "/help",
+        );
+    }
+}
+
+fn list_command(server: &mut Server, author_addr: SocketAddr, _args: &str) {
+    let mut nicks = server
+        .clients
+        .values()
+        .filter(|client| client.authed && !client.nick.is_empty())
+        .map(|client| client.nick.as_str())
+        .collect::<Vec<_>>();
+    nicks.sort_unstable();
+    if let Some(author) = server.clients.get(&author_addr) {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server
+                .config
+                .line(Kind::Roster, None, nicks.join(" "), server.clock.now()),
+            "user list",
+        );
+    }
+}
+
+fn msg_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let send_error = |error: String| {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server
+                .config
+                .line(Kind::System, None, error.red().bold(), server.clock.now()),
+            "/msg error",
+        );
+    };
+
+    let (nick, text) = args.split_once(' ').unwrap_or((args, ""));
+    let text = text.trim();
+    if text.is_empty() {
+        send_error("Usage: /msg <nick> <text>".to_string());
+        return;
+    }
+    let target = server
+        .clients
+        .iter()
+        .find(|(_, client)| client.authed && !client.nick.is_empty() && client.nick == nick);
+    let Some((&target_addr, target)) = target else {
+        send_error(format!("No such user: {nick}"));
+        return;
+    };
+
+    let now = server.clock.now();
+    let _ = send_line(
+        target.conn.as_ref(),
+        target_addr,
+        server.config.line(
+            Kind::Message,
+            None,
+            format_args!("*{}* {text}", author.nick),
+            now,
+        ),
+        "private message",
+    );
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server
+            .config
+            .line(Kind::Message, None, format_args!("-> *{nick}* {text}"), now),
+        "private message echo",
+    );
+    if let Some(away) = &target.away {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server.config.line(
+                Kind::System,
+                None,
+                format_args!("{nick} is away: {away}"),
+                now,
+            ),
+            "away reply",
+        );
+    }
+}
+
+/// Whether `text` goes out to the sender's channel: a plain message or a
+/// `/me` action.
+fn is_chat(text: &str) -> bool {
+    match text.trim().strip_prefix('/') {
+        Some(command) => command.split(' ').next() == Some("me"),
+        None => true,
+    }
+}
+
+fn me_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    if args.is_empty() {
+        server.command_error(author_addr, "me", "Usage: /me <action>");
+        return;
+    }
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let line = format!(
+        "* {} {}",
+        author.nick,
+        filter_words(&server.filtered_words, args)
+    );
+    let channel = author.channel.clone();
+    server.broadcast(author_addr, &channel, Kind::Message, None, &line);
+    server.sent_to_channel(channel);
+}
+
+fn away_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(author) = server.clients.get_mut(&author_addr) else {
+        return;
+    };
+    let reply = if args.is_empty() {
+        if author.away.take().is_some() {
+            "You are no longer marked as away"
+        } else {
+            "You are not marked as away"
+        }
+    } else {
+        author.away = Some(args.to_string());
+        "You are marked as away, /away again once you are back"
+    };
+    print_debug(format!(
+        "{} is {}",
+        author.nick,
+        if author.away.is_some() {
+            "away"
+        } else {
+            "back"
+        }
+    ));
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server
+            .config
+            .line(Kind::System, None, reply, server.clock.now()),
+        "/away reply",
+    );
+}
+
+fn join_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let in_use = server.channels_in_use(author_addr);
+    let error = if !args.starts_with('#') || args.len() < 2 {
+        Some("Usage: /join #channel")
+    } else if args.len() > MAX_CHANNEL_LEN {
+        Some("Channel name is too long!")
+    } else if args.chars().any(|x| x.is_whitespace() || x.is_control()) {
+        Some("Channel names can't contain spaces")
+    } else if in_use.len() >= server.config.max_channels && !in_use.contains(args) {
+        Some("Too many channels are open, join one that is already in use")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        if let Some(author) = server.clients.get(&author_addr) {
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                server
+                    .config
+                    .line(Kind::System, None, error.red().bold(), server.clock.now()),
+                "/join error",
+            );
+        }
+        return;
+    }
+    server.move_to_channel(author_addr, args);
+}
+
+fn part_command(server: &mut Server, author_addr: SocketAddr, _args: &str) {
+    server.move_to_channel(author_addr, DEFAULT_CHANNEL);
+}
+
+fn topic_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let channel = author.channel.clone();
+    if args.is_empty() {
+        if server.topics.contains_key(&channel) {
+            server.send_topic(author_addr);
+        } else {
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                server.config.line(
+                    Kind::System,
+                    None,
+                    format_args!("No topic set for {channel}"),
+                    server.clock.now(),
+                ),
+                "/topic reply",
+            );
+        }
+        return;
+    }
+    if !server.config.open_topic && !server.require_admin(author_addr, "topic") {
+        return;
+    }
+
+    let nick = author.nick.clone();
+    print_info(format!(
+        "{} set the topic of {channel} to {args:?}",
+        Sens(author_addr)
+    ));
+    server.topics.insert(channel.clone(), args.to_string());
+    let notice = format!("*** {nick} set the topic: {args}");
+    if let Some(author) = server.clients.get(&author_addr) {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server
+                .config
+                .line(Kind::System, None, &notice, server.clock.now()),
+            "/topic reply",
+        );
+    }
+    server.broadcast(author_addr, &channel, Kind::System, None, &notice);
+}
+
+fn nick_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let error = if args.is_empty() {
+        Some("Usage: /nick <name>")
+    } else if server.accounts.contains_key(args) {
+        Some("That nickname is registered, reconnect and pick it to log in")
+    } else {
+        server.nick_error(args)
+    };
+    let Some(author) = server.clients.get_mut(&author_addr) else {
+        return;
+    };
+    if let Some(error) = error {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server
+                .config
+                .line(Kind::System, None, error.red().bold(), server.clock.now()),
+            "/nick error",
+        );
+        return;
+    }
+
+    let old_nick = std::mem::replace(&mut author.nick, args.to_string());
+    let channel = author.channel.clone();
+    print_info(format!(
+        "{} renamed from {old_nick} to {args}",
+        Sens(author_addr)
+    ));
+    let line = format!("*** {old_nick} is now {args}");
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server
+            .config
+            .line(Kind::System, None, &line, server.clock.now()),
+        "nick notice",
+    );
+    server.broadcast(author_addr, &channel, Kind::System, None, &line);
+}
+
+fn register_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(path) = server.config.accounts_path.clone() else {
+        server.command_error(author_addr, "register", "Accounts are off on this server");
+        return;
+    };
+    if args.is_empty() {
+        server.command_error(author_addr, "register", "Usage: /register <password>");
+        return;
+    }
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let nick = author.nick.clone();
+    if server.accounts.contains_key(&nick) {
+        server.command_error(
+            author_addr,
+            "register",
+            "Your nickname is already registered",
+        );
+        return;
+    }
+    let Ok(hash) = hash_password(args) else {
+        server.command_error(
+            author_addr,
+            "register",
+            "Could not register, try again later",
+        );
+        return;
+    };
+    print_info(format!(
+        "{} registered the nickname {nick}",
+        Sens(author_addr)
+    ));
+    server.accounts.insert(nick.clone(), hash);
+    save_accounts(&path, &server.accounts);
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server.config.line(
+            Kind::System,
+            None,
+            format_args!("{nick} is yours now, you'll be asked for the password next time"),
+            server.clock.now(),
+        ),
+        "/register reply",
+    );
+}
+
+fn stats_command(server: &mut Server, author_addr: SocketAddr, _args: &str) {
+    if !server.require_admin(author_addr, "stats") {
+        return;
+    }
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let now = server.clock.now();
+    let authed = server
+        .clients
+        .values()
+        .filter(|client| client.authed)
+        .count();
+    let uptime = now.duration_since(server.started_at).unwrap_or_default();
+    let stats = format!(
+        "Clients: {} ({authed} authed, {} not), messages: {}, bans: {}, bytes in: {}, out: {}, up for {}",
+        server.clients.len(),
+        server.clients.len() - authed,
+        server.metrics.messages.load(Ordering::Relaxed),
+        server.metrics.bans.load(Ordering::Relaxed),
+        server.metrics.bytes_in.load(Ordering::Relaxed),
+        server.metrics.bytes_out.load(Ordering::Relaxed),
+        format_duration(uptime)
+    );
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server.config.line(Kind::System, None, stats, now),
+        "/stats",
+    );
+}
+
+fn whois_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(target_addr) = server.admin_target(author_addr, "whois", args) else {
+        return;
+    };
+    let (Some(author), Some(target)) = (
+        server.clients.get(&author_addr),
+        server.clients.get(&target_addr),
+    ) else {
+        return;
+    };
+    let now = server.clock.now();
+    let online = now.duration_since(target.connected_at).unwrap_or_default();
+    let whois = format!(
+        "{}: address {}, connected at {} ({} ago), in {}, strikes: {}, authed: {}, bytes in: {}, out: {}",
+        target.nick,
+        Sens(target_addr),
+        server.config.timestamp.format(target.connected_at),
+        format_duration(online),
+        target.channel,
+        target.strike_count,
+        if target.authed { "yes" } else { "no" },
+        target.bytes_in.load(Ordering::Relaxed),
+        target.bytes_out.load(Ordering::Relaxed)
+    );
+    let _ = send_line(
+        author.conn.as_ref(),
+        author_addr,
+        server.config.line(Kind::System, None, whois, now),
+        "/whois",
+    );
+}
+
+fn kick_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let Some(target_addr) = server.admin_target(author_addr, "kick", args) else {
+        return;
+    };
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    print_info(format!("{} kicked {args}", Sens(author_addr)));
+    let notice = format!("You have been kicked by {}", author.nick)
+        .red()
+        .bold();
+    server.drop_client(target_addr, Some(&notice.to_string()), "was kicked");
+}
+
+fn ban_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    let (nick, minutes) = args.split_once(' ').unwrap_or((args, ""));
+    let now = server.clock.now();
+    // A length the clock can't count up to is refused like a malformed one.
+    let length = match minutes.trim() {
+        "" => Some(server.config.ban_limit),
+        minutes => minutes
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| *minutes > 0)
+            .and_then(|minutes| minutes.checked_mul(60))
+            .map(Duration::from_secs),
+    };
+    let Some((length, banned_until)) =
+        length.and_then(|length| Some((length, now.checked_add(length)?)))
+    else {
+        server.command_error(author_addr, "ban", "Usage: /ban <nick> [minutes]");
+        return;
+    };
+    let Some(target_addr) = server.admin_target(author_addr, "ban", nick) else {
+        return;
+    };
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+
+    let ip = ban_key(target_addr.ip());
+    print_warn(format!(
+        "{} banned {nick} ({}) for {} secs",
+        Sens(author_addr),
+        Sens(ip),
+        length.as_secs()
+    ));
+    let notice = format!(
+        "You have been banned by {} for {} minutes",
+        author.nick,
+        length.as_secs().div_ceil(60)
+    )
+    .red()
+    .bold()
+    .to_string();
+    server.banned_mfs.insert(ip, banned_until);
+    server.metrics.bans.fetch_add(1, Ordering::Relaxed);
+    save_bans(&server.config.bans_path, &server.banned_mfs);
+
+    let banned = server
+        .clients
+        .keys()
+        .filter(|addr| ban_key(addr.ip()) == ip)
+        .copied()
+        .collect::<Vec<_>>();
+    for addr in banned {
+        server.drop_client(addr, Some(&notice), "was banned");
+    }
+}
+
+fn bans_command(server: &mut Server, author_addr: SocketAddr, _args: &str) {
+    if !server.require_admin(author_addr, "bans") {
+        return;
+    }
+    let Some(author) = server.clients.get(&author_addr) else {
+        return;
+    };
+    let now = server.clock.now();
+    let mut bans = server
+        .banned_mfs
+        .iter()
+        .filter(|(_, until)| now < **until)
+        .map(|(ip, until)| (ip, until.duration_since(now).unwrap_or_default()))
+        .collect::<Vec<_>>();
+    bans.sort_unstable_by_key(|(_, left)| *left);
+    let lines = if bans.is_empty() {
+        vec!["No active bans".to_string()]
+    } else {
+        iter::once("Bans:".to_string())
+            .chain(
+                bans.iter()
+                    .map(|(ip, left)| format!("{}  {} left", Sens(ip), format_duration(*left))),
+            )
+            .collect()
+    };
+    for line in lines {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server.config.line(Kind::System, None, line, now),
+            "/bans",
+        );
+    }
+}
+
+fn unban_command(server: &mut Server, author_addr: SocketAddr, args: &str) {
+    if !server.require_admin(author_addr, "unban") {
+        return;
+    }
+    let Ok(ip) = args.parse::<IpAddr>().map(ban_key) else {
+        server.command_error(author_addr, "unban", "Usage: /unban <ip>");
+        return;
+    };
+    let reply = if server.banned_mfs.remove(&ip).is_some() {
+        print_info(format!("{} unbanned {}", Sens(author_addr), Sens(ip)));
+        save_bans(&server.config.bans_path, &server.banned_mfs);
+        format!("Unbanned {ip}")
+    } else {
+        format!("{ip} is not banned")
+    };
+    if let Some(author) = server.clients.get(&author_addr) {
+        let _ = send_line(
+            author.conn.as_ref(),
+            author_addr,
+            server
+                .config
+                .line(Kind::System, None, reply, server.clock.now()),
+            "/unban reply",
+        );
+    }
+}
+
+/// Formats a duration like `1h 02m 03s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// When the ban on each IP runs out, keyed by [`ban_key`].
+type Bans = HashMap<IpAddr, SystemTime>;
+
+/// The key a ban on `ip` goes under. A v4-mapped IPv6 address is turned back
+/// into plain IPv4, so a ban holds across both address families.
+fn ban_key(ip: IpAddr) -> IpAddr {
+    ip.to_canonical()
+}
+
+/// Loads bans stored as `<ip> <expiry unix secs>` lines, dropping the ones
+/// that already expired. A missing or malformed file yields no bans.
+fn load_bans(path: &Path) -> Bans {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                print_info(format!(
+                    "no bans file at {}, starting without bans",
+                    path.display()
+                ));
+            } else {
+                print_error(format!(
+                    "could not read bans from {}: {err}",
+                    path.display()
+                ));
+            }
+            return Bans::new();
+        }
+    };
+
+    let now = SystemTime::now();
+    let mut bans = Bans::new();
+    for (index, line) in content.lines().enumerate() {
+        let entry = line.split_once(' ').and_then(|(ip, secs)| {
+            let ip = ban_key(ip.parse::<IpAddr>().ok()?);
+            let secs = secs.trim().parse::<u64>().ok()?;
+            Some((ip, UNIX_EPOCH.checked_add(Duration::from_secs(secs))?))
+        });
+        let Some((ip, banned_until)) = entry else {
+            print_error(format!(
+                "malformed ban entry at {}:{}, ignoring the bans file",
+                path.display(),
+                index + 1
+            ));
+            return Bans::new();
+        };
+        if now < banned_until {
+            bans.insert(ip, banned_until);
+        }
+    }
+    bans
+}
+
+/// Reads the message of the day, one line per notice, falling back to
+/// [`DEFAULT_MOTD`] without a file. A file that can't be read means no
+/// message at all.
+fn load_motd(path: Option<&Path>) -> Vec<String> {
+    let Some(path) = path else {
+        return vec![DEFAULT_MOTD.to_string()];
+    };
+    match fs::read_to_string(path) {
+        Ok(content) => content.lines().map(str::to_string).collect(),
+        Err(err) => {
+            print_warn(format!(
+                "could not read the message of the day from {}, going without: {err}",
+                path.display()
+            ));
+            Vec::new()
+        }
+    }
+}
+
+/// Reads the words to filter out of messages, one per line, lowercased so
+/// they match whatever the case.
+fn load_filter(path: Option<&Path>) -> HashSet<String> {
+    let Some(path) = path else {
+        return HashSet::new();
+    };
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let words = content
+                .lines()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect::<HashSet<_>>();
+            print_info(format!("filtering {} words", words.len()));
+            words
+        }
+        Err(err) => {
+            print_warn(format!(
+                "could not read the word filter from {}, going without: {err}",
+                path.display()
+            ));
+            HashSet::new()
+        }
+    }
+}
+
+/// Stars out every whole word of `text` that is in `words`, whatever its
+/// case.
+fn filter_words(words: &HashSet<String>, text: &str) -> String {
+    if words.is_empty() {
+        return text.to_string();
+    }
+    let mut filtered = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let word_len = rest
+            .find(|x: char| !x.is_alphanumeric())
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(word_len);
+        if words.contains(&word.to_lowercase()) {
+            filtered.push_str(&"*".repeat(word.chars().count()));
+        } else {
+            filtered.push_str(word);
+        }
+        let gap_len = after.find(char::is_alphanumeric).unwrap_or(after.len());
+        let (gap, after) = after.split_at(gap_len);
+        filtered.push_str(gap);
+        rest = after;
+    }
+    filtered
+}
+
+fn save_bans(path: &Path, bans: &Bans) {
+    let mut content = String::new();
+    for (ip, banned_until) in bans.iter() {
+        let secs = banned_until
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let _ = writeln!(&mut content, "{ip} {secs}");
+    }
+    let _ = fs::write(path, content).map_err(|err| {
+        print_error(format!("could not save bans to {}: {err}", path.display()));
+    });
+}
+
+/// The password hash of every registered nickname.
+type Accounts = HashMap<String, String>;
+
+/// Reads the accounts file, one `<nick> <hash>` per line.
+fn load_accounts(path: &Path) -> Accounts {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                print_info(format!(
+                    "no accounts file at {}, starting without accounts",
+                    path.display()
+                ));
+            } else {
+                print_error(format!(
+                    "could not read accounts from {}: {err}",
+                    path.display()
+                ));
+            }
+            return Accounts::new();
+        }
+    };
+
+    let mut accounts = Accounts::new();
+    for (index, line) in content.lines().enumerate() {
+        let Some((nick, hash)) = line.split_once(' ') else {
+            print_error(format!(
+                "malformed account entry at {}:{}, skipping it",
+                path.display(),
+                index + 1
+            ));
+            continue;
+        };
+        accounts.insert(nick.to_string(), hash.trim().to_string());
+    }
+    print_info(format!("loaded {} accounts", accounts.len()));
+    accounts
+}
+
+fn save_accounts(path: &Path, accounts: &Accounts) {
+    let mut content = String::new();
+    for (nick, hash) in accounts.iter() {
+        let _ = writeln!(&mut content, "{nick} {hash}");
+    }
+    let _ = fs::write(path, content).map_err(|err| {
+        print_error(format!(
+            "could not save accounts to {}: {err}",
+            path.display()
+        ));
+    });
+}
+
+/// Hashes a password with Argon2 and a random salt, giving a PHC string
+/// that carries the salt and parameters along.
+fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; Salt::RECOMMENDED_LENGTH];
+    getrandom::getrandom(&mut salt).map_err(|err| {
+        print_error(format!("could not generate a password salt: {err}"));
+    })?;
+    let salt = SaltString::encode_b64(&salt).map_err(|err| {
+        print_error(format!("could not encode a password salt: {err}"));
+    })?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| {
+            print_error(format!("could not hash a password: {err}"));
+        })
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Arc::new(Mutex::new(now)))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// How the server stamps broadcast messages. The time always comes from the
+/// server so every client sees the same value.
+pub struct TimestampFormat {
+    pub utc: bool,
+    pub seconds: bool,
+}
+
+impl TimestampFormat {
+    fn format(&self, time: SystemTime) -> String {
+        let pattern = if self.seconds { "%H:%M:%S" } else { "%H:%M" };
+        if self.utc {
+            DateTime::<Utc>::from(time).format(pattern).to_string()
+        } else {
+            DateTime::<Local>::from(time).format(pattern).to_string()
+        }
+    }
+}
+
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Where `/metrics` is served over HTTP, on the `bind` address. Off when
+    /// not set.
+    pub metrics_port: Option<u16>,
+    pub ban_limit: Duration,
+    pub message_rate: Duration,
+    pub message_burst: usize,
+    /// Minimum time between two messages in the same channel, whoever
+    /// sends them. Off when not set.
+    pub channel_rate: Option<Duration>,
+    pub strike_limit: i32,
+    /// Strikes after which a client is warned that a ban is coming, half of
+    /// `strike_limit` when not set.
+    pub strike_warning: Option<i32>,
+    /// Whether the strike and failed token limits actually ban, rather than
+    /// only logging that they would.
+    pub enforce: bool,
+    pub max_conns_per_ip: usize,
+    pub max_clients: usize,
+    /// How many channels may have someone in them at once, counting the
+    /// default one. Joining an empty channel past that is refused.
+    pub max_channels: usize,
+    pub idle_timeout: Duration,
+    /// How long a write to one client may hold up the server thread before
+    /// that client is dropped, so a stuck client can't stall everyone else.
+    pub write_timeout: Duration,
+    /// How long a client's reader waits for a single byte before giving up
+    /// on the connection. Clients answer every keepalive, so it only runs
+    /// out on dead or stalled connections.
+    pub read_timeout: Duration,
+    pub auth_timeout: Duration,
+    pub auth_attempts: usize,
+    pub auth_window: Duration,
+    pub bans_path: PathBuf,
+    /// Where registered nicknames and their password hashes are kept, with
+    /// accounts turned off when not set.
+    pub accounts_path: Option<PathBuf>,
+    pub timestamp: TimestampFormat,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub admin_token: Option<String>,
+    pub admin_ips: Vec<IpAddr>,
+    pub log_file: Option<PathBuf>,
+    /// Where to write the generated token instead of printing it.
+    pub token_file: Option<PathBuf>,
+    pub log_max_bytes: u64,
+    pub json: bool,
+    pub read_buffer: usize,
+    pub echo: bool,
+    /// Whether anyone may set a channel's topic, not only admins.
+    pub open_topic: bool,
+    pub acks: bool,
+    pub motd_file: Option<PathBuf>,
+    /// Words to star out of messages, one per line. No filtering when not
+    /// set.
+    pub filter_path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            metrics_port: None,
+            ban_limit: DEFAULT_BAN_LIMIT,
+            message_rate: DEFAULT_MESSAGE_RATE,
+            message_burst: DEFAULT_MESSAGE_BURST,
+            channel_rate: None,
+            strike_limit: DEFAULT_STRIKE_LIMIT,
+            strike_warning: None,
+            enforce: true,
+            max_conns_per_ip: DEFAULT_MAX_CONNS_PER_IP,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            auth_timeout: DEFAULT_AUTH_TIMEOUT,
+            auth_attempts: DEFAULT_AUTH_ATTEMPTS,
+            auth_window: DEFAULT_AUTH_WINDOW,
+            bans_path: PathBuf::from(BANS_PATH),
+            accounts_path: None,
+            timestamp: TimestampFormat {
+                utc: false,
+                seconds: true,
+            },
+            tls_cert: None,
+            tls_key: None,
+            admin_token: None,
+            admin_ips: Vec::new(),
+            log_file: None,
+            token_file: None,
+            log_max_bytes: DEFAULT_LOG_MAX_BYTES,
+            json: false,
+            read_buffer: DEFAULT_READ_BUFFER,
+            echo: false,
+            open_topic: false,
+            acks: false,
+            motd_file: None,
+            filter_path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Formats a line for a client, tagged with its kind or as a JSON
+    /// [`Frame`] with `--json`. Messages are stamped with `time`, and with
+    /// `nick` as their author when they come straight from a user.
+    fn line(
+        &self,
+        kind: Kind,
+        nick: Option<&str>,
+        text: impl fmt::Display,
+        time: SystemTime,
+    ) -> String {
+        if self.json {
+            let frame = Frame {
+                nick: nick.map(str::to_string),
+                text: strip_colors(&text.to_string()),
+                ts: time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+                kind,
+            };
+            serde_json::to_string(&frame).expect("frames only hold strings and numbers")
+        } else if kind == Kind::Message {
+            let timestamp = self.timestamp.format(time);
+            match nick {
+                Some(nick) => format!("{kind} [{timestamp}] <{nick}> {text}"),
+                None => format!("{kind} [{timestamp}] {text}"),
+            }
+        } else {
+            format!("{kind} {text}")
+        }
+    }
+}
+
+/// The characters of `text` that show on screen, with their byte offsets,
+/// leaving out the terminal color codes.
+pub fn visible_chars(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut chars = text.char_indices();
+    iter::from_fn(move || loop {
+        let (i, x) = chars.next()?;
+        if x != '\x1b' {
+            return Some((i, x));
+        }
+        // Skip the rest of the code, which ends with a letter.
+        for (_, x) in chars.by_ref() {
+            if x.is_ascii_alphabetic() {
+                break;
+            }
+        }
+    })
+}
+
+/// Drops the terminal color codes from `text`, which JSON consumers and
+/// saved transcripts have no use for.
+pub fn strip_colors(text: &str) -> String {
+    visible_chars(text).map(|(_, x)| x).collect()
+}
+
+/// The chat state machine. Everything the server knows lives here and only
+/// changes through [`Server::handle_message`].
+pub struct Server {
+    clients: Clients,
+    banned_mfs: Bans,
+    accounts: Accounts,
+    failed_auths: HashMap<IpAddr, Vec<SystemTime>>,
+    motd: Vec<String>,
+    /// The topic of every channel that has been given one.
+    topics: HashMap<String, String>,
+    /// When each channel last had a message, for `--channel-rate`.
+    channel_last: HashMap<String, SystemTime>,
+    /// Words starred out of messages, lowercased.
+    filtered_words: HashSet<String>,
+    started_at: SystemTime,
+    metrics: Arc<Metrics>,
+    last_ping: SystemTime,
+    token: String,
+    config: Config,
+    clock: Box<dyn Clock + Send>,
+}
+
+impl Server {
+    pub fn new(token: String, config: Config) -> Self {
+        Self::with_clock(token, config, SystemClock)
+    }
+
+    /// Like [`Server::new`], but reads the current time from `clock` so tests
+    /// can move time forward without sleeping.
+    pub fn with_clock(token: String, config: Config, clock: impl Clock + Send + 'static) -> Self {
+        Self {
+            clients: Clients::new(),
+            banned_mfs: load_bans(&config.bans_path),
+            accounts: config
+                .accounts_path
+                .as_deref()
+                .map(load_accounts)
+                .unwrap_or_default(),
+            failed_auths: HashMap::new(),
+            motd: load_motd(config.motd_file.as_deref()),
+            topics: HashMap::new(),
+            channel_last: HashMap::new(),
+            filtered_words: load_filter(config.filter_path.as_deref()),
+            started_at: clock.now(),
+            metrics: Arc::new(Metrics::default()),
+            last_ping: clock.now(),
+            token,
+            config,
+            clock: Box::new(clock),
+        }
+    }
+
+    /// The server's counters, which stay readable from other threads once
+    /// the server is running.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned_mfs
+            .get(&ban_key(ip))
+            .is_some_and(|banned_until| self.clock.now() < *banned_until)
+    }
+
+    /// Handles messages until a [`Message::Shutdown`] arrives, or until
+    /// nobody is left to send any, which shuts down the same way.
+    pub fn run(mut self, messages: Receiver<Message>) -> Result<()> {
+        loop {
+            let Ok(msg) = messages.recv() else {
+                print_info("no more messages for the server, shutting down");
+                self.shutdown();
+                return Ok(());
+            };
+            let shutdown = matches!(msg, Message::Shutdown);
+            self.handle_message(msg);
+            if shutdown {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn handle_message(&mut self, msg: Message) {
+        match msg {
+            Message::ClientConnected {
+                author,
+                author_addr,
+                accepted,
+            } => {
+                let _ = accepted.send(self.client_connected(author, author_addr));
+            }
+            Message::ClientDisconnected { author_addr } => self.client_disconnected(author_addr),
+            Message::NewMessage { author_addr, bytes } => self.new_message(author_addr, bytes),
+            Message::MessageTooLong { author_addr } => {
+                print_warn(format!(
+                    "Client {} sent a message longer than {MAX_MESSAGE_LEN} bytes",
+                    Sens(author_addr)
+                ));
+                let too_long_msg = "Message too long!".red().bold();
+                self.drop_client(author_addr, Some(&too_long_msg.to_string()), "left");
+            }
+            Message::Tick => self.tick(),
+            Message::Shutdown => self.shutdown(),
+        }
+    }
+
+    /// Decides whether to take a new client, telling it why not otherwise.
+    fn client_connected(
+        &mut self,
+        author: Arc<dyn Connection>,
+        author_addr: SocketAddr,
+    ) -> Option<Arc<dyn Connection>> {
+        let bytes_in = Arc::new(AtomicU64::new(0));
+        let bytes_out = Arc::new(AtomicU64::new(0));
+        let author: Arc<dyn Connection> = Arc::new(Metered::new(
+            author,
+            self.metrics.clone(),
+            bytes_in.clone(),
+            bytes_out.clone(),
+        ));
+        let now = self.clock.now();
+        let ip = ban_key(author_addr.ip());
+        let banned_until = self
+            .banned_mfs
+            .remove(&ip)
+            .filter(|banned_until| now < *banned_until);
+
+        if let Some(banned_until) = banned_until {
+            self.banned_mfs.insert(ip, banned_until);
+            let secs = banned_until
+                .duration_since(now)
+                .unwrap_or_default()
+                .as_secs_f32();
+            print_warn(format!(
+                "Client {} tried to connect, who is banned for {secs} secs",
+                Sens(author_addr)
+            ));
+            let _ = send_line(
+                author.as_ref(),
+                author_addr,
+                self.config.line(
+                    Kind::System,
+                    None,
+                    format_args!("You are banned MF: {secs} secs left"),
+                    now,
+                ),
+                "banned message",
+            );
+            let _ = author.shutdown().map_err(|err| {
+                print_error(format!(
+                    "could not shut down socket for {}: {}",
+                    Sens(author_addr),
+                    Sens(err)
+                ))
+            });
+            None
+        } else if self.clients.len() >= self.config.max_clients {
+            print_warn(format!(
+                "Client {} rejected, the server is full with {} clients",
+                Sens(author_addr),
+                self.clients.len()
+            ));
+            let _ = send_line(
+                author.as_ref(),
+                author_addr,
+                self.config
+                    .line(Kind::System, None, "Server full, try again later", now),
+                "server full message",
+            );
+            let _ = author.shutdown().map_err(|err| {
+                print_error(format!(
+                    "could not shut down socket for {}: {}",
+                    Sens(author_addr),
+                    Sens(err)
+                ))
+            });
+            None
+        } else if self
+            .clients
+            .keys()
+            .filter(|addr| addr.ip() == author_addr.ip())
+            .count()
+            >= self.config.max_conns_per_ip
+        {
+            print_warn(format!(
+                "Client {} rejected, its address already has {} connections",
+                Sens(author_addr),
+                self.config.max_conns_per_ip
+            ));
+            let _ = send_line(
+                author.as_ref(),
+                author_addr,
+                self.config.line(
+                    Kind::System,
+                    None,
+                    "Too many connections from your address",
+                    now,
+                ),
+                "connection limit message",
+            );
+            let _ = author.shutdown().map_err(|err| {
+                print_error(format!(
+                    "could not shut down socket for {}: {}",
+                    Sens(author_addr),
+                    Sens(err)
+                ))
+            });
+            None
+        } else {
+            print_info(format!("Client {} connected", Sens(author_addr)));
+            self.clients.insert(
+                author_addr,
+                Client {
+                    conn: author.clone(),
+                    last_message: now,
+                    last_seen: now,
+                    connected_at: now,
+                    allowance: self.config.message_burst,
+                    refilled_at: now,
+                    queued: VecDeque::new(),
+                    received: 0,
+                    bytes_in,
+                    bytes_out,
+                    strike_count: 0,
+                    authed: false,
+                    admin: false,
+                    nick: String::new(),
+                    claiming: None,
+                    away: None,
+                    channel: DEFAULT_CHANNEL.to_string(),
+                },
+            );
+            self.count_clients();
+            if self.config.echo {
+                let _ = send_line(
+                    author.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::Control, None, ECHO, now),
+                    "echo announcement",
+                );
+            }
+            if self.config.acks {
+                let _ = send_line(
+                    author.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::Control, None, ACKS, now),
+                    "acks announcement",
+                );
+            }
+            let token_str = TOKEN_PROMPT.bright_yellow().underline().bold();
+            let _ = send_line(
+                author.as_ref(),
+                author_addr,
+                self.config.line(Kind::System, None, token_str, now),
+                "Token prompt",
+            );
+            Some(author)
+        }
+    }
+
+    fn client_disconnected(&mut self, author_addr: SocketAddr) {
+        let Some(author) = self.clients.remove(&author_addr) else {
+            // Already dropped by the server, e.g. kicked or timed out.
+            print_info(format!("Client {} disconnected", Sens(author_addr)));
+            return;
+        };
+        self.count_clients();
+        let session = self
+            .clock
+            .now()
+            .duration_since(author.connected_at)
+            .unwrap_or_default();
+        print_info(format!(
+            "Client {} disconnected after {}",
+            Sens(author_addr),
+            format_duration(session)
+        ));
+        if author.authed && !author.nick.is_empty() {
+            self.broadcast(
+                author_addr,
+                &author.channel,
+                Kind::Leave,
+                None,
+                &format!("{} left", author.nick),
+            );
+            self.send_user_count();
+        }
+    }
+
+    fn tick(&mut self) {
+        let now = self.clock.now();
+        let stale = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                now.duration_since(client.last_seen)
+                    .is_ok_and(|idle| idle >= KEEPALIVE_TIMEOUT)
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in stale {
+            print_info(format!("Client {} timed out", Sens(addr)));
+            self.drop_client(addr, None, "left");
+        }
+
+        let idle = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client.authed
+                    && now
+                        .duration_since(client.last_message)
+                        .is_ok_and(|idle| idle >= self.config.idle_timeout)
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in idle {
+            print_info(format!("Client {} was idle for too long", Sens(addr)));
+            let idle_msg = "You have been idle for too long, see you later!"
+                .bright_blue()
+                .bold();
+            self.drop_client(addr, Some(&idle_msg.to_string()), "left");
+        }
+
+        let unauthed = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                !client.authed
+                    && now
+                        .duration_since(client.connected_at)
+                        .is_ok_and(|waited| waited >= self.config.auth_timeout)
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in unauthed {
+            print_info(format!(
+                "Client {} did not authenticate in time",
+                Sens(addr)
+            ));
+            let auth_msg = "Took too long to enter the token, disconnecting"
+                .red()
+                .bold();
+            self.drop_client(addr, Some(&auth_msg.to_string()), "left");
+        }
+
+        self.failed_auths.retain(|_, attempts| {
+            attempts.retain(|at| {
+                now.duration_since(*at)
+                    .is_ok_and(|age| age < self.config.auth_window)
+            });
+            !attempts.is_empty()
+        });
+
+        let waiting = self
+            .clients
+            .iter()
+            .filter(|(_, client)| !client.queued.is_empty())
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        for addr in waiting {
+            self.flush_queue(addr);
+        }
+
+        if now
+            .duration_since(self.last_ping)
+            .is_ok_and(|since| since < KEEPALIVE_INTERVAL)
+        {
+            return;
+        }
+        self.last_ping = now;
+        for (addr, client) in self.clients.iter() {
+            let _ = send_line(
+                client.conn.as_ref(),
+                *addr,
+                self.config.line(Kind::Control, None, PING, now),
+                "keepalive",
+            );
+        }
+    }
+
+    fn shutdown(&mut self) {
+        let shutdown_msg = "*** server shutting down".red().bold();
+        for (addr, client) in self.clients.iter() {
+            if client.authed {
+                let _ = send_line(
+                    client.conn.as_ref(),
+                    *addr,
+                    self.config
+                        .line(Kind::System, None, &shutdown_msg, self.clock.now()),
+                    "shutdown notice",
+                );
+            }
+            let _ = client.conn.shutdown().map_err(|err| {
+                print_error(format!(
+                    "could not shutdown socket for {}: {}",
+                    Sens(addr),
+                    Sens(err)
+                ));
+            });
+        }
+        print_info("server shut down");
+    }
+
+    fn new_message(&mut self, author_addr: SocketAddr, bytes: Vec<u8>) {
+        // Readers only start once their client is registered, see
+        // `register`, so this is a client that was dropped already.
+        let Some(author) = self.clients.get_mut(&author_addr) else {
+            return;
+        };
+        let now = self.clock.now();
+        author.last_seen = now;
+        if bytes.trim_ascii() == PONG_LINE.as_bytes() {
+            print_trace(format!(
+                "Client {} answered the keepalive",
+                Sens(author_addr)
+            ));
+            return;
+        }
+        if self.config.acks {
+            author.received += 1;
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                self.config.line(
+                    Kind::Control,
+                    None,
+                    format_args!("{ACK}{}", author.received),
+                    now,
+                ),
+                "ack",
+            );
+        }
+        let Ok(text) = str::from_utf8(&bytes) else {
+            print_debug(format!(
+                "Client {} sent invalid bytes {bytes:?}",
+                Sens(author_addr)
+            ));
+            author.strike_count += 1;
+            self.metrics.strikes.fetch_add(1, Ordering::Relaxed);
+            if author.strike_count >= self.config.strike_limit && !self.config.enforce {
+                print_warn(format!(
+                    "Client {} would have been banned, but --no-enforce is set",
+                    Sens(author_addr)
+                ));
+                author.strike_count = 0;
+            } else if author.strike_count >= self.config.strike_limit {
+                print_warn(format!("Client {} got banned", Sens(author_addr)));
+                self.banned_mfs
+                    .insert(ban_key(author_addr.ip()), now + self.config.ban_limit);
+                save_bans(&self.config.bans_path, &self.banned_mfs);
+                self.metrics.bans.fetch_add(1, Ordering::Relaxed);
+                let _ = send_line(
+                    author.conn.as_ref(),
+                    author_addr,
+                    self.config
+                        .line(Kind::System, None, "You are banned MF", now),
+                    "banned message",
+                );
+                let _ = author.conn.shutdown().map_err(|err| {
+                    print_error(format!(
+                        "could not shutdown socket for {}: {}",
+                        Sens(author_addr),
+                        Sens(err)
+                    ))
+                });
+            } else if author.strike_count
+                == self
+                    .config
+                    .strike_warning
+                    .unwrap_or(self.config.strike_limit / 2)
+            {
+                print_debug(format!("Client {} got a strike warning", Sens(author_addr)));
+                let warning = "Warning: stop sending garbage or you'll be banned"
+                    .red()
+                    .bold();
+                let _ = send_line(
+                    author.conn.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::System, None, warning, now),
+                    "strike warning",
+                );
+            }
+            return;
+        };
+
+        // Only the line ending goes, whitespace the author typed stays.
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+
+        // Typing fast is not abuse, so messages over the rate are held back
+        // rather than counted as strikes.
+        author.queued.push_back(text.to_string());
+        if author.queued.len() > MAX_QUEUED_MESSAGES {
+            author.queued.pop_front();
+            print_debug(format!("Client {} is sending too fast", Sens(author_addr)));
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                self.config.line(
+                    Kind::System,
+                    None,
+                    "You are sending messages too fast, the oldest waiting one was dropped".red(),
+                    now,
+                ),
+                "rate limit notice",
+            );
+        }
+        self.flush_queue(author_addr);
+    }
+
+    /// Handles the client's queued messages for as long as the rate limit
+    /// lets it send them.
+    fn flush_queue(&mut self, author_addr: SocketAddr) {
+        let now = self.clock.now();
+        while let Some(author) = self.clients.get(&author_addr) {
+            let Some(text) = author.queued.front() else {
+                break;
+            };
+            // Chat messages, `/me` included, also wait for their channel's turn.
+            let chat = author.authed && !author.nick.is_empty() && is_chat(text);
+            if chat && self.channel_busy(&author.channel, now) {
+                break;
+            }
+            let Some(author) = self.clients.get_mut(&author_addr) else {
+                break;
+            };
+            if !author.take_allowance(now, self.config.message_rate, self.config.message_burst) {
+                break;
+            }
+            if let Some(text) = author.queued.pop_front() {
+                self.handle_text(author_addr, &text);
+            }
+        }
+    }
+
+    /// Updates [`Metrics::clients`] after a client came or went.
+    fn count_clients(&self) {
+        self.metrics
+            .clients
+            .store(self.clients.len(), Ordering::Relaxed);
+    }
+
+    /// The channels authenticated clients other than `except` are in. Left
+    /// out like that, a client alone in its channel can still move to a new
+    /// one at the cap, since that doesn't add to how many are in use.
+    fn channels_in_use(&self, except: SocketAddr) -> HashSet<&str> {
+        self.clients
+            .iter()
+            .filter(|(addr, client)| **addr != except && client.authed)
+            .map(|(_, client)| client.channel.as_str())
+            .collect()
+    }
+
+    /// Counts a message that went out to `channel` and starts its
+    /// `--channel-rate` wait.
+    fn sent_to_channel(&mut self, channel: String) {
+        self.metrics.messages.fetch_add(1, Ordering::Relaxed);
+        if self.config.channel_rate.is_some() {
+            self.channel_last.insert(channel, self.clock.now());
+        }
+    }
+
+    /// Whether `channel` had a message less than `--channel-rate` ago.
+    fn channel_busy(&self, channel: &str, now: SystemTime) -> bool {
+        self.config.channel_rate.is_some_and(|rate| {
+            self.channel_last.get(channel).is_some_and(|last| {
+                now.duration_since(*last)
+                    .is_ok_and(|elapsed| elapsed < rate)
+            })
+        })
+    }
+
+    fn handle_text(&mut self, author_addr: SocketAddr, text: &str) {
+        let Some(author) = self.clients.get_mut(&author_addr) else {
+            return;
+        };
+        let now = self.clock.now();
+        author.last_message = now;
+        author.strike_count = 0;
+
+        if author.authed && author.nick.is_empty() {
+            if let Some(nick) = author.claiming.take() {
+                let hash = self.accounts.get(&nick);
+                if hash.is_some_and(|hash| verify_password(hash, text.trim())) {
+                    self.register_nick(author_addr, &nick);
+                } else {
+                    print_warn(format!(
+                        "{} gave the wrong password for {nick}",
+                        Sens(author_addr)
+                    ));
+                    let wrong_password_msg =
+                        "Wrong password, disconnecting in 3.. 2.. 1..".red().bold();
+                    self.failed_auth(author_addr, &wrong_password_msg.to_string());
+                }
+                return;
+            }
+            let nick = text.trim();
+            let conn = author.conn.clone();
+            let error = self.nick_error(nick);
+            if let Some(error) = error {
+                let _ = send_line(
+                    conn.as_ref(),
+                    author_addr,
+                    self.config
+                        .line(Kind::System, None, error.red().bold(), now),
+                    "nickname error",
+                );
+                let nick_str = "Please enter your nickname:"
+                    .bright_yellow()
+                    .underline()
+                    .bold();
+                let _ = send_line(
+                    conn.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::System, None, nick_str, now),
+                    "nickname prompt",
+                );
+            } else if self.accounts.contains_key(nick) {
+                if let Some(author) = self.clients.get_mut(&author_addr) {
+                    author.claiming = Some(nick.to_string());
+                }
+                let _ = send_line(
+                    conn.as_ref(),
+                    author_addr,
+                    self.config.line(
+                        Kind::System,
+                        None,
+                        PASSWORD_PROMPT.bright_yellow().underline().bold(),
+                        now,
+                    ),
+                    "password prompt",
+                );
+            } else {
+                self.register_nick(author_addr, nick);
+            }
+        } else if author.authed && text.starts_with('/') {
+            // Never log the password that comes with /register.
+            let logged = if text.trim_start().starts_with("/register") {
+                "/register ..."
+            } else {
+                text
+            };
+            print_debug(format!(
+                "Client {} sent command {logged:?}",
+                Sens(author_addr)
+            ));
+            self.run_command(author_addr, text.trim());
+        } else if author.authed {
+            print_debug(format!(
+                "Client {} sent message {text:?}",
+                Sens(author_addr)
+            ));
+            let nick = author.nick.clone();
+            let channel = author.channel.clone();
+            let text = filter_words(&self.filtered_words, text);
+            self.broadcast(author_addr, &channel, Kind::Message, Some(&nick), &text);
+            self.sent_to_channel(channel);
+        } else {
+            let trimmed_text = text.trim();
+            let invalid_token_msg = format!("{INVALID_TOKEN}, disconnecting in 3.. 2.. 1..")
+                .red()
+                .bold();
+            let admin = self.config.admin_token.as_deref() == Some(trimmed_text);
+            if admin || trimmed_text == self.token {
+                author.authed = true;
+                author.admin = admin || self.config.admin_ips.contains(&author_addr.ip());
+                if author.admin {
+                    print_info(format!("{} is an admin", Sens(author_addr)));
+                }
+                let welcome_msg = "Welcome to the Club!".green().bold();
+                let _ = send_line(
+                    author.conn.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::System, None, welcome_msg, now),
+                    "welcome message",
+                );
+                for line in self.motd.iter() {
+                    let _ = send_line(
+                        author.conn.as_ref(),
+                        author_addr,
+                        self.config.line(Kind::System, None, line, now),
+                        "the message of the day",
+                    );
+                }
+                let nick_str = "Please enter your nickname:"
+                    .bright_yellow()
+                    .underline()
+                    .bold();
+                let _ = send_line(
+                    author.conn.as_ref(),
+                    author_addr,
+                    self.config.line(Kind::System, None, nick_str, now),
+                    "nickname prompt",
+                );
+            } else {
+                self.failed_auth(author_addr, &invalid_token_msg.to_string());
+            }
+        }
+    }
+
+    fn register_nick(&mut self, author_addr: SocketAddr, nick: &str) {
+        let Some(author) = self.clients.get_mut(&author_addr) else {
+            return;
+        };
+        print_info(format!("{} registered as {nick}", Sens(author_addr)));
+        author.nick = nick.to_string();
+        let channel = author.channel.clone();
+        self.broadcast(
+            author_addr,
+            &channel,
+            Kind::Join,
+            None,
+            &format!("{nick} joined"),
+        );
+        self.send_user_count();
+        self.send_topic(author_addr);
+    }
+
+    /// Counts a wrong token or password against the client's address and
+    /// disconnects it, with `notice` unless that gets the address banned.
+    fn failed_auth(&mut self, author_addr: SocketAddr, notice: &str) {
+        let Some(author) = self.clients.remove(&author_addr) else {
+            return;
+        };
+        self.count_clients();
+        let now = self.clock.now();
+        print_warn(format!("{} failed authorization!", Sens(author_addr)));
+        // Failures count per IP, since every attempt comes on a fresh
+        // connection.
+        let attempts = self.failed_auths.entry(author_addr.ip()).or_default();
+        attempts.retain(|at| {
+            now.duration_since(*at)
+                .is_ok_and(|age| age < self.config.auth_window)
+        });
+        attempts.push(now);
+        let failures = attempts.len();
+        let limit_reached = failures >= self.config.auth_attempts;
+        if limit_reached && self.config.enforce {
+            print_warn(format!(
+                "Client {} got banned after {failures} failed authorizations",
+                Sens(author_addr)
+            ));
+            self.failed_auths.remove(&author_addr.ip());
+            self.banned_mfs
+                .insert(ban_key(author_addr.ip()), now + self.config.ban_limit);
+            save_bans(&self.config.bans_path, &self.banned_mfs);
+            self.metrics.bans.fetch_add(1, Ordering::Relaxed);
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                self.config
+                    .line(Kind::System, None, "You are banned MF", now),
+                "banned message",
+            );
+        } else {
+            if limit_reached {
+                print_warn(format!(
+                    "Client {} would have been banned after {failures} failed authorizations, but --no-enforce is set", Sens(author_addr)
+                ));
+                self.failed_auths.remove(&author_addr.ip());
+            }
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                self.config.line(Kind::System, None, notice, now),
+                "failed authorization notice",
+            );
+        }
+        let _ = author.conn.shutdown().map_err(|err| {
+            print_error(format!(
+                "could not shutdown {}: {}",
+                Sens(author_addr),
+                Sens(err)
+            ))
+        });
+    }
+
+    fn run_command(&mut self, author_addr: SocketAddr, line: &str) {
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        if let Some(command) = COMMANDS.iter().find(|command| command.name == name) {
+            (command.run)(self, author_addr, args.trim());
+        } else if let Some(author) = self.clients.get(&author_addr) {
+            let unknown_msg = format!("Unknown command /{name}, see /help").red().bold();
+            let _ = send_line(
+                author.conn.as_ref(),
+                author_addr,
+                self.config
+                    .line(Kind::System, None, unknown_msg, self.clock.now()),
+                "unknown command notice",
+            );
+        }
+    }
+
+    /// Sends `text` as a `kind` line to every registered client in `channel`
+    /// except the author, who only gets messages back with `--echo`. `nick`
+    /// marks messages straight from a user.
+    fn broadcast(
+        &mut self,
+        author_addr: SocketAddr,
+        channel: &str,
+        kind: Kind,
+        nick: Option<&str>,
+        text: &str,
+    ) {
+        let line = self.config.line(kind, nick, text, self.clock.now());
+        let echo = self.config.echo && kind == Kind::Message;
+        let mut dead = Vec::new();
+        for (addr, client) in self.clients.iter() {
+            if (*addr != author_addr || echo)
+                && client.authed
+                && !client.nick.is_empty()
+                && client.channel == channel
+                && send_line(client.conn.as_ref(), *addr, &line, "broadcast").is_err()
+            {
+                dead.push(*addr);
+            }
+        }
+        self.drop_dead(dead);
+    }
+
+    /// Drops the clients a write to has failed, so they aren't written to
+    /// again until their reader notices.
+    fn drop_dead(&mut self, dead: Vec<SocketAddr>) {
+        for addr in dead {
+            print_info(format!(
+                "Client {} dropped, its connection is gone",
+                Sens(addr)
+            ));
+            self.drop_client(addr, None, "left");
+        }
+    }
+
+    /// Tells every registered client how many users are online.
+    fn send_user_count(&mut self) {
+        let registered = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.authed && !client.nick.is_empty())
+            .collect::<Vec<_>>();
+        let mut dead = Vec::new();
+        for (addr, client) in registered.iter() {
+            let line = self.config.line(
+                Kind::Control,
+                None,
+                format_args!("{USERS}{}", registered.len()),
+                self.clock.now(),
+            );
+            if send_line(client.conn.as_ref(), **addr, line, "user count").is_err() {
+                dead.push(**addr);
+            }
+        }
+        self.drop_dead(dead);
+    }
+
+    /// Checks a nickname a client wants to register or switch to.
+    fn nick_error(&self, nick: &str) -> Option<&'static str> {
+        if nick.is_empty() {
+            Some("Nickname can't be empty!")
+        } else if nick.len() > MAX_NICK_LEN {
+            Some("Nickname is too long!")
+        } else if nick.chars().any(|x| x.is_whitespace() || x.is_control()) {
+            Some("Nicknames can't contain spaces")
+        } else if self.clients.values().any(|client| client.nick == nick) {
+            Some("Nickname is already taken!")
+        } else {
+            None
+        }
+    }
+
+    /// Moves a client to `channel`, announcing the switch in both the old and
+    /// the new channel.
+    fn move_to_channel(&mut self, addr: SocketAddr, channel: &str) {
+        let Some(client) = self.clients.get_mut(&addr) else {
+            return;
+        };
+        if client.channel == channel {
+            let _ = send_line(
+                client.conn.as_ref(),
+                addr,
+                self.config.line(
+                    Kind::System,
+                    None,
+                    format_args!("You are already in {channel}"),
+                    self.clock.now(),
+                ),
+                "channel notice",
+            );
+            return;
+        }
+
+        let old_channel = std::mem::replace(&mut client.channel, channel.to_string());
+        let nick = client.nick.clone();
+        print_info(format!(
+            "{} moved from {old_channel} to {channel}",
+            Sens(addr)
+        ));
+        let _ = send_line(
+            client.conn.as_ref(),
+            addr,
+            self.config.line(
+                Kind::System,
+                None,
+                format_args!("*** You are now in {channel}"),
+                self.clock.now(),
+            ),
+            "channel notice",
+        );
+        self.broadcast(
+            addr,
+            &old_channel,
+            Kind::Leave,
+            None,
+            &format!("{nick} left {old_channel}"),
+        );
+        self.broadcast(
+            addr,
+            channel,
+            Kind::Join,
+            None,
+            &format!("{nick} joined {channel}"),
+        );
+        self.send_topic(addr);
+    }
+
+    /// Tells a client the topic of its channel, if it has one.
+    fn send_topic(&self, addr: SocketAddr) {
+        let Some(client) = self.clients.get(&addr) else {
+            return;
+        };
+        let Some(topic) = self.topics.get(&client.channel) else {
+            return;
+        };
+        let _ = send_line(
+            client.conn.as_ref(),
+            addr,
+            self.config.line(
+                Kind::System,
+                None,
+                format_args!("Topic for {}: {topic}", client.channel),
+                self.clock.now(),
+            ),
+            "topic",
+        );
+    }
+
+    /// Disconnects a client, telling its channel that it `departure`d, e.g.
+    /// "left" or "was kicked".
+    fn drop_client(&mut self, addr: SocketAddr, notice: Option<&str>, departure: &str) {
+        if let Some(client) = self.clients.remove(&addr) {
+            self.count_clients();
+            if let Some(notice) = notice {
+                let _ = send_line(
+                    client.conn.as_ref(),
+                    addr,
+                    self.config
+                        .line(Kind::System, None, notice, self.clock.now()),
+                    "disconnect notice",
+                );
+            }
+            let _ = client.conn.shutdown().map_err(|err| {
+                print_error(format!(
+                    "could not shutdown socket for {}: {}",
+                    Sens(addr),
+                    Sens(err)
+                ));
+            });
+            if client.authed && !client.nick.is_empty() {
+                self.broadcast(
+                    addr,
+                    &client.channel,
+                    Kind::Leave,
+                    None,
+                    &format!("{} {departure}", client.nick),
+                );
+                self.send_user_count();
+            }
+        }
+    }
+
+    /// Sends a command's error to the client that ran it.
+    fn command_error(&self, addr: SocketAddr, command: &str, error: &str) {
+        if let Some(client) = self.clients.get(&addr) {
+            let _ = send_line(
+                client.conn.as_ref(),
+                addr,
+                self.config
+                    .line(Kind::System, None, error.red().bold(), self.clock.now()),
+                &format!("/{command} error"),
+            );
+        }
+    }
+
+    /// Checks that the author of an admin command is one.
+    fn require_admin(&self, author_addr: SocketAddr, command: &str) -> bool {
+        let admin = self
+            .clients
+            .get(&author_addr)
+            .is_some_and(|author| author.admin);
+        if !admin {
+            self.command_error(author_addr, command, "Only admins can do that");
+        }
+        admin
+    }
+
+    /// Resolves the target of an admin command, telling the author why if
+    /// there is none.
+    fn admin_target(
+        &self,
+        author_addr: SocketAddr,
+        command: &str,
+        nick: &str,
+    ) -> Option<SocketAddr> {
+        if !self.require_admin(author_addr, command) {
+            return None;
+        }
+        if nick.is_empty() {
+            self.command_error(author_addr, command, &format!("Usage: /{command} <nick>"));
+            return None;
+        }
+        let target = self
+            .clients
+            .iter()
+            .find(|(_, client)| client.authed && client.nick == nick)
+            .map(|(addr, _)| *addr);
+        if target.is_none() {
+            self.command_error(author_addr, command, &format!("No such user: {nick}"));
+        }
+        target
+    }
+}
+
+/// Listens on `address`. An IPv6 address also takes IPv4 clients where the
+/// OS allows it, they show up as v4-mapped IPv6 addresses.
+pub fn listen(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        let _ = socket.set_only_v6(false).map_err(|err| {
+            print_warn(format!(
+                "could not take IPv4 clients on {address}, only IPv6 ones: {err}"
+            ))
+        });
+    }
+    // Same as `TcpListener::bind`, so a restart doesn't wait out TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Hands a new connection to the server thread and waits for it to decide
+/// whether to take the client. Only accepted connections get a reader, which
+/// reads from the connection returned here so the bytes are counted.
+///
+/// This is what orders the handshake: the token prompt is written while
+/// [`Message::ClientConnected`] is handled, and [`client`] must not start
+/// reading before this returns. Whatever the client sent in the meantime,
+/// even a token typed before the prompt showed up, waits in the socket and
+/// reaches the server thread after the client is known, so its first line
+/// is always taken as the token.
+pub fn register(
+    stream: Arc<dyn Connection>,
+    author_addr: SocketAddr,
+    messages: &Sender<Message>,
+) -> Result<Option<Arc<dyn Connection>>> {
+    let (accepted_sender, accepted) = channel();
+    messages
+        .send(Message::ClientConnected {
+            author: stream,
+            author_addr,
+            accepted: accepted_sender,
+        })
+        .map_err(|err| {
+            print_error(format!(
+                "could not send new connection to the server thread: {}",
+                Sens(err)
+            ))
+        })?;
+    accepted.recv().map_err(|err| {
+        print_error(format!(
+            "server thread did not answer for a new connection: {err}"
+        ))
+    })
+}
+
+/// Reads lines from a client, `read_buffer` bytes at a time, and hands them
+/// to the server thread.
+pub fn client(
+    stream: Arc<dyn Connection>,
+    author_addr: SocketAddr,
+    read_buffer: usize,
+    messages: Sender<Message>,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    let mut temp_buffer = vec![0; read_buffer.clamp(1, MAX_MESSAGE_LEN)];
+    loop {
+        let n = match stream.read(&mut temp_buffer) {
+            Ok(n) => n,
+            // Nothing came in within the read timeout, treated like the
+            // client hanging up.
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                print_info(format!(
+                    "Client {} sent nothing for too long",
+                    Sens(author_addr)
+                ));
+                0
+            }
+            Err(err) => {
+                print_error(format!("could not read message from client: {err}"));
+                let _ = messages
+                    .send(Message::ClientDisconnected { author_addr })
+                    .map_err(|err| {
+                        print_error(format!(
+                            "could not sent message to the server thread: {err}"
+                        ))
+                    });
+                return Err(());
+            }
+        };
+        if n > 0 {
+            buffer.extend_from_slice(&temp_buffer[..n]);
+            // One read can hold several lines, pass them all on right away.
+            while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                let complete_message = buffer.drain(..=pos).collect::<Vec<_>>();
+                messages
+                    .send(Message::NewMessage {
+                        author_addr,
+                        bytes: complete_message,
+                    })
+                    .map_err(|err| {
+                        print_error(format!(
+                            "could not send message to the server thread: {err}"
+                        ));
+                    })?;
+            }
+            if buffer.len() > MAX_MESSAGE_LEN {
+                let _ = messages
+                    .send(Message::MessageTooLong { author_addr })
+                    .map_err(|err| {
+                        print_error(format!(
+                            "could not sent message to the server thread: {err}"
+                        ))
+                    });
+                break;
+            }
+        } else {
+            let _ = messages
+                .send(Message::ClientDisconnected { author_addr })
+                .map_err(|err| {
+                    print_error(format!(
+                        "could not sent message to the server thread: {err}"
+                    ))
+                });
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Counters kept by the server thread, served to Prometheus over plain HTTP
+//! by a server started with `--metrics-port`.
+
+use crate::{print_error, print_info, print_warn, Connection, Sens};
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How long a scrape may take before its connection is given up on, so a
+/// slow one can't hold up the next.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// More than enough for the request line and the headers Prometheus sends.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Written by the server thread, read by whoever serves them.
+#[derive(Default)]
+pub struct Metrics {
+    pub clients: AtomicUsize,
+    pub messages: AtomicU64,
+    pub bans: AtomicU64,
+    pub strikes: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+impl Metrics {
+    /// The counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(text, "# HELP tchat_{name} {help}");
+            let _ = writeln!(text, "# TYPE tchat_{name} {kind}");
+            let _ = writeln!(text, "tchat_{name} {value}");
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "clients",
+            "gauge",
+            "Clients connected right now.",
+            self.clients.load(Ordering::Relaxed) as u64,
+        );
+        metric(
+            "messages_total",
+            "counter",
+            "Messages sent to channels.",
+            load(&self.messages),
+        );
+        metric(
+            "bans_total",
+            "counter",
+            "Bans handed out.",
+            load(&self.bans),
+        );
+        metric(
+            "strikes_total",
+            "counter",
+            "Messages that were not valid UTF-8.",
+            load(&self.strikes),
+        );
+        metric(
+            "received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            load(&self.bytes_in),
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            load(&self.bytes_out),
+        );
+        text
+    }
+}
+
+/// A client connection that adds everything read from it to
+/// [`Metrics::bytes_in`] and to `received`, and everything written to it to
+/// [`Metrics::bytes_out`] and to `sent`, the counts for that one client.
+pub struct Metered {
+    conn: Arc<dyn Connection>,
+    metrics: Arc<Metrics>,
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+}
+
+impl Metered {
+    pub fn new(
+        conn: Arc<dyn Connection>,
+        metrics: Arc<Metrics>,
+        received: Arc<AtomicU64>,
+        sent: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            conn,
+            metrics,
+            received,
+            sent,
+        }
+    }
+}
+
+impl Connection for Metered {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.conn.read(buf)?;
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+        self.metrics.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        self.conn.write_all(bytes)?;
+        self.sent.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        self.metrics
+            .bytes_out
+            .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.conn.shutdown()
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with [`Metrics::render`], one
+/// request per connection, until accepting fails for good.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let _ = scrape(stream, &metrics).map_err(|err| {
+                    print_warn(format!("could not answer a metrics request: {}", Sens(err)));
+                });
+            }
+            Err(err) => {
+                print_error(format!("could not accept metrics connection: {err}"));
+            }
+        }
+    }
+    print_info("no longer serving metrics");
+}
+
+fn scrape(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    // Only the request line matters, but the headers are read too so closing
+    // the socket doesn't reset the connection under the response.
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.trim_ascii().split(|&b| b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", metrics.render()),
+        (Some(b"GET"), _) => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is served\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    )
+}
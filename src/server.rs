@@ -1,310 +1,298 @@
-use colored::Colorize;
 use getrandom::getrandom;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use std::{
-    collections::HashMap,
-    fmt::{self, Write as OtherWrite},
-    io::{Read, Write},
-    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
-    result, str,
+    env,
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process,
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
         Arc,
     },
     thread,
     time::{Duration, SystemTime},
 };
+use tchat::{
+    client, listen, metrics, print_error, print_info, print_warn, register, set_log_file,
+    tls::{self, TlsStream},
+    Config, Connection, Level, Message, Result, Sens, Server, LOG_LEVEL, MAX_MESSAGE_LEN,
+    SAFE_MODE, TICK_INTERVAL,
+};
 
-type Result<T> = result::Result<T, ()>;
-
-const PORT: u16 = 6969;
-const SAFE_MODE: bool = false;
-const BAN_LIMIT: Duration = Duration::from_secs(10 * 60);
-const MESSAGE_RATE: Duration = Duration::from_secs(1);
-const STRIKE_LIMIT: i32 = 10;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
-struct Sens<T>(T);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
-impl<T: fmt::Display> fmt::Display for Sens<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self(inner) = self;
-        if SAFE_MODE {
-            writeln!(f, "[REDACTED]")
-        } else {
-            inner.fmt(f)
-        }
-    }
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String> {
+    args.next().ok_or_else(|| {
+        print_error(format!("{flag} expects a value"));
+    })
 }
 
-fn print_error<T: fmt::Display>(message: T) {
-    eprintln!("{}: {}", "ERROR".bold().red(), message);
-}
-
-fn print_info<T: fmt::Display>(message: T) {
-    println!("{}: {}", "INFO".bold().truecolor(99, 105, 132), message);
+/// Parses a positive number of seconds, fractions allowed, short enough
+/// that the clock can count that far ahead of now.
+fn parse_secs(flag: &str, value: &str) -> Result<Duration> {
+    let secs = value
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        // Too small to tell apart from no time at all.
+        .filter(|secs| !secs.is_zero())
+        .ok_or_else(|| {
+            print_error(format!(
+                "invalid {flag} {value}: expected a positive number of seconds"
+            ));
+        })?;
+    if SystemTime::now().checked_add(secs).is_none() {
+        print_error(format!("invalid {flag} {value}: too long"));
+        return Err(());
+    }
+    Ok(secs)
 }
 
-enum Message {
-    ClientConnected {
-        author: Arc<TcpStream>,
-    },
-    ClientDisconnected {
-        author_addr: SocketAddr,
-    },
-    NewMessage {
-        author_addr: SocketAddr,
-        bytes: Vec<u8>,
-    },
+/// Applies a `--color` mode to the log output. `auto` only colors when both
+/// stdout and stderr are terminals, so redirected logs stay plain text.
+fn set_color(mode: &str) -> Result<()> {
+    let enabled = match mode {
+        "always" => true,
+        "never" => false,
+        "auto" => io::stdout().is_terminal() && io::stderr().is_terminal(),
+        _ => {
+            print_error(format!(
+                "invalid --color {mode}: expected always, never or auto"
+            ));
+            return Err(());
+        }
+    };
+    colored::control::set_override(enabled);
+    Ok(())
 }
 
-struct Client {
-    conn: Arc<TcpStream>,
-    last_message: SystemTime,
-    strike_count: i32,
-    authed: bool,
+/// Parses a whole number of at least 1.
+fn parse_count(flag: &str, value: &str) -> Result<usize> {
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|count| *count >= 1)
+        .ok_or_else(|| {
+            print_error(format!(
+                "invalid {flag} {value}: expected a whole number of at least 1"
+            ));
+        })
 }
 
-fn server(messages: Receiver<Message>, token: String) -> Result<()> {
-    let mut clients = HashMap::<SocketAddr, Client>::new();
-    let mut banned_mfs = HashMap::<IpAddr, SystemTime>::new();
-    loop {
-        let msg = messages.recv().expect("The server receiver is not hung up");
-        match msg {
-            Message::ClientConnected { author } => {
-                let author_addr = author
-                    .peer_addr()
-                    .expect("TODO: cache the peer addrs of the connection");
-                let mut banned_at = banned_mfs.remove(&author_addr.ip());
-                let now = SystemTime::now();
+fn parse_args() -> Result<Config> {
+    let mut config = Config::default();
+    let mut level = Level::Info;
 
-                banned_at = banned_at.and_then(|banned_at| {
-                    let diff = now
-                        .duration_since(banned_at)
-                        .expect("TODO: don't crash if the clock went backwards");
-                    if diff >= BAN_LIMIT {
-                        None
-                    } else {
-                        Some(banned_at)
-                    }
-                });
-
-                if let Some(banned_at) = banned_at {
-                    let diff = now
-                        .duration_since(banned_at)
-                        .expect("TODO: don't crash if the clock went backwards");
-                    banned_mfs.insert(author_addr.ip().clone(), banned_at);
-                    let mut author = author.as_ref();
-                    let secs = (BAN_LIMIT - diff).as_secs_f32();
-                    print_info(format!(
-                        "Client {author_addr} tried to connect, who is banned for {secs} secs"
-                    ));
-                    let _ =
-                        writeln!(author, "You are banned MF: {secs} secs left",).map_err(|err| {
-                            print_error(format!(
-                                "could not send banned message to {author_addr}: {err}"
-                            ))
-                        });
-                    let _ = author.shutdown(Shutdown::Both).map_err(|err| {
-                        print_error(format!(
-                            "could not shut down socket for {author_addr}: {err}"
-                        ))
-                    });
-                } else {
-                    print_info(format!("Client {author_addr} connected"));
-                    clients.insert(
-                        author_addr.clone(),
-                        Client {
-                            conn: author.clone(),
-                            last_message: now - 2 * MESSAGE_RATE,
-                            strike_count: 0,
-                            authed: false,
-                        },
-                    );
-                    let token_str = "Please enter the Token:".bright_yellow().underline().bold();
-                    let _ = write!(author.as_ref(), "{}", token_str).map_err(|err| {
+    let mut args = env::args();
+    let _program = args.next().expect("program name");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--idle-timeout" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.idle_timeout = parse_secs(&arg, &value)?;
+            }
+            "--write-timeout" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.write_timeout = parse_secs(&arg, &value)?;
+            }
+            "--read-timeout" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.read_timeout = parse_secs(&arg, &value)?;
+            }
+            "--auth-timeout" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.auth_timeout = parse_secs(&arg, &value)?;
+            }
+            "--auth-attempts" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.auth_attempts = parse_count(&arg, &value)?;
+            }
+            "--auth-window" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.auth_window = parse_secs(&arg, &value)?;
+            }
+            "--ban-limit" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.ban_limit = parse_secs(&arg, &value)?;
+            }
+            "--message-rate" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.message_rate = parse_secs(&arg, &value)?;
+            }
+            "--message-burst" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.message_burst = parse_count(&arg, &value)?;
+            }
+            "--channel-rate" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.channel_rate = Some(parse_secs(&arg, &value)?);
+            }
+            "--strike-limit" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.strike_limit = value
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|limit| *limit >= 1)
+                    .ok_or_else(|| {
                         print_error(format!(
-                            "could not send Token prompt to {}: {}",
-                            Sens(author_addr),
-                            Sens(err)
+                            "invalid {arg} {value}: expected a whole number of at least 1"
                         ));
-                    });
-                }
+                    })?;
             }
-            Message::ClientDisconnected { author_addr } => {
-                print_info(format!("Client {author_addr} disconnected"));
-                clients.remove(&author_addr);
-            }
-            Message::NewMessage { author_addr, bytes } => {
-                if let Some(author) = clients.get_mut(&author_addr) {
-                    let now = SystemTime::now();
-                    let diff = now
-                        .duration_since(author.last_message)
-                        .unwrap_or_else(|err| {
-                            print_error(format!("message rate check on new message: the clock might have gone backwards: {err}"));
-                            Duration::from_secs(0)
-                        });
-                    if diff >= MESSAGE_RATE {
-                        if let Ok(text) = str::from_utf8(&bytes) {
-                            author.last_message = now;
-                            author.strike_count = 0;
-
-                            let bytes_without_last = if !bytes.is_empty() {
-                                &bytes[..bytes.len() - 1]
-                            } else {
-                                &bytes[..]
-                            };
-
-                            if author.authed {
-                                print_info(format!(
-                                    "Client {author_addr} sent message {bytes_without_last:?}"
-                                ));
-                                for (addr, client) in clients.iter() {
-                                    if *addr != author_addr && client.authed {
-                                        let _ = writeln!(client.conn.as_ref(), "{text}").map_err(|err| {
-                                        print_error(format!("could not broadcast message to all the clients from {author_addr}: {err}"))
-                                    });
-                                    }
-                                }
-                            } else {
-                                let trimmed_text = text.trim();
-                                let invalid_token_msg =
-                                    "Invalid token!, disconnecting in 3.. 2.. 1..".red().bold();
-                                if trimmed_text == token {
-                                    author.authed = true;
-                                    let welcome_msg = "Welcome to the Club!".green().bold();
-                                    let _ = writeln!(author.conn.as_ref(), "{}", welcome_msg)
-                                        .map_err(|err| {
-                                            print_error(format!(
-                                                "could not send welcome message to {}: {}",
-                                                Sens(author_addr),
-                                                Sens(err)
-                                            ));
-                                        });
-                                } else {
-                                    print_info(format!(
-                                        "{} failed authorization!",
-                                        Sens(author_addr)
-                                    ));
-                                    let _ = writeln!(author.conn.as_ref(), "{}", invalid_token_msg)
-                                        .map_err(|err| {
-                                            print_error(format!(
-                                            "could not notify client {} about invalid token: {}",
-                                            Sens(author_addr),
-                                            Sens(err)
-                                        ));
-                                        });
-                                    let _ = author.conn.shutdown(Shutdown::Both).map_err(|err| {
-                                        print_error(format!(
-                                            "could not shutdown {}: {}",
-                                            Sens(author_addr),
-                                            Sens(err)
-                                        ))
-                                    });
-                                    clients.remove(&author_addr);
-                                }
-                            }
-                        } else {
-                            author.strike_count += 1;
-                            if author.strike_count >= STRIKE_LIMIT {
-                                print_info(format!("Client {author_addr} got banned"));
-                                banned_mfs.insert(author_addr.ip().clone(), now);
-                                let _ = writeln!(author.conn.as_ref(), "You are banned MF")
-                                    .map_err(|err| {
-                                        print_error(format!(
-                                            "could not send banned message to {author_addr}: {err}"
-                                        ))
-                                    });
-                                let _ = author.conn.shutdown(Shutdown::Both).map_err(|err| {
-                                    print_error(format!(
-                                        "could not shutdown socket for {author_addr}: {err}"
-                                    ))
-                                });
-                            }
-                        }
-                    } else {
-                        author.strike_count += 1;
-                        if author.strike_count >= STRIKE_LIMIT {
-                            print_info(format!("Client {author_addr} got banned"));
-                            banned_mfs.insert(author_addr.ip().clone(), now);
-                            let _ = writeln!(author.conn.as_ref(), "You are banned MF").map_err(
-                                |err| {
-                                    print_error(format!(
-                                        "could not send banned message to {author_addr}: {err}"
-                                    ))
-                                },
-                            );
-                            let _ = author.conn.shutdown(Shutdown::Both).map_err(|err| {
-                                print_error(format!(
-                                    "could not shutdown socket for {author_addr}: {err}"
-                                ))
-                            });
-                        }
-                    }
-                }
+            "--strike-warning" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.strike_warning = Some(
+                    value
+                        .parse::<i32>()
+                        .ok()
+                        .filter(|warning| *warning >= 1)
+                        .ok_or_else(|| {
+                            print_error(format!(
+                                "invalid {arg} {value}: expected a whole number of at least 1"
+                            ));
+                        })?,
+                );
             }
-        }
-    }
-}
-
-fn client(stream: Arc<TcpStream>, messages: Sender<Message>) -> Result<()> {
-    let author_addr = stream.peer_addr().map_err(|err| {
-        print_error(format!("could not get peer address: {err}"));
-    })?;
-
-    messages
-        .send(Message::ClientConnected {
-            author: stream.clone(),
-        })
-        .map_err(|err| {
-            print_error(format!(
-                "could not sent message from {author_addr} to the server thread: {err}",
-                author_addr = Sens(author_addr),
-                err = Sens(err)
-            ))
-        })?;
-
-    let mut buffer = Vec::new();
-    loop {
-        let mut temp_buffer = [0; 512]; // Temporary buffer for reading data
-        let n = stream.as_ref().read(&mut temp_buffer).map_err(|err| {
-            print_error(format!("could not read message from client: {err}"));
-            let _ = messages
-                .send(Message::ClientDisconnected { author_addr })
-                .map_err(|err| {
-                    print_error(format!(
-                        "could not sent message to the server thread: {err}"
-                    ))
-                });
-        })?;
-        if n > 0 {
-            buffer.extend_from_slice(&temp_buffer[..n]);
-            if let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-                let complete_message = buffer.drain(..=pos).collect::<Vec<_>>();
-                messages
-                    .send(Message::NewMessage {
-                        author_addr,
-                        bytes: complete_message,
-                    })
-                    .map_err(|err| {
+            "--max-clients" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.max_clients = parse_count(&arg, &value)?;
+            }
+            "--max-channels" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.max_channels = parse_count(&arg, &value)?;
+            }
+            "--max-conns-per-ip" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.max_conns_per_ip = parse_count(&arg, &value)?;
+            }
+            "-v" | "--verbose" => {
+                level = level.more_verbose();
+            }
+            "-vv" => {
+                level = level.more_verbose().more_verbose();
+            }
+            "--utc" => {
+                config.timestamp.utc = true;
+            }
+            "--no-seconds" => {
+                config.timestamp.seconds = false;
+            }
+            "--color" => {
+                set_color(&flag_value(&mut args, &arg)?)?;
+            }
+            _ if arg.starts_with("--color=") => {
+                set_color(&arg["--color=".len()..])?;
+            }
+            "--admin-ip" => {
+                let value = flag_value(&mut args, &arg)?;
+                let ip = value.parse::<IpAddr>().map_err(|err| {
+                    print_error(format!("invalid {arg} {value}: {err}"));
+                })?;
+                // Compared with client addresses, which are canonical too.
+                config.admin_ips.push(ip.to_canonical());
+            }
+            "--bind" => {
+                let value = flag_value(&mut args, &arg)?;
+                // IPv6 addresses may come in brackets, like in a URL.
+                let addr = value.trim_start_matches('[').trim_end_matches(']');
+                config.bind = addr.parse::<IpAddr>().map_err(|err| {
+                    print_error(format!("invalid {arg} {value}: {err}"));
+                })?;
+            }
+            "--metrics-port" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.metrics_port = Some(value.parse::<u16>().map_err(|err| {
+                    print_error(format!("invalid {arg} {value}: {err}"));
+                })?);
+            }
+            "--accounts-file" => {
+                config.accounts_path = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--token-file" => {
+                config.token_file = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--log-file" => {
+                config.log_file = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--log-max-bytes" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.log_max_bytes = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|bytes| *bytes >= 1)
+                    .ok_or_else(|| {
                         print_error(format!(
-                            "could not send message to the server thread: {err}"
+                            "invalid {arg} {value}: expected a positive number of bytes"
                         ));
                     })?;
             }
-        } else {
-            let _ = messages
-                .send(Message::ClientDisconnected { author_addr })
-                .map_err(|err| {
+            "--read-buffer" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.read_buffer = parse_count(&arg, &value)?;
+                if config.read_buffer > MAX_MESSAGE_LEN {
                     print_error(format!(
-                        "could not sent message to the server thread: {err}"
-                    ))
-                });
-            break;
+                        "invalid {arg} {value}: can't be more than the {MAX_MESSAGE_LEN} bytes a message can take"
+                    ));
+                    return Err(());
+                }
+            }
+            "--filter-file" => {
+                config.filter_path = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--motd-file" => {
+                config.motd_file = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--no-enforce" => {
+                config.enforce = false;
+            }
+            "--acks" => {
+                config.acks = true;
+            }
+            "--open-topic" => {
+                config.open_topic = true;
+            }
+            "--echo" => {
+                config.echo = true;
+            }
+            "--json" => {
+                config.json = true;
+            }
+            "--tls-cert" => {
+                config.tls_cert = Some(flag_value(&mut args, &arg)?.into());
+            }
+            "--tls-key" => {
+                config.tls_key = Some(flag_value(&mut args, &arg)?.into());
+            }
+            port => {
+                config.port = port.parse::<u16>().map_err(|err| {
+                    print_error(format!("invalid port {port}: {err}"));
+                })?;
+            }
         }
     }
-    Ok(())
+    if config
+        .strike_warning
+        .is_some_and(|warning| warning >= config.strike_limit)
+    {
+        print_error("--strike-warning must be below --strike-limit");
+        return Err(());
+    }
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(config)
 }
 
-fn main() -> Result<()> {
+fn random_token() -> String {
     let mut buffer: [u8; 16] = [0; 16];
     let _ = getrandom(&mut buffer).map_err(|err| {
         print_error(format!("could not generate random access token: {err}"));
@@ -314,24 +302,198 @@ fn main() -> Result<()> {
     for x in buffer.iter() {
         let _ = write!(&mut token, "{x:02X}");
     }
+    token
+}
 
-    println!("Token: {token}");
+/// Writes the token to `path`, readable only by its owner on Unix, so it
+/// doesn't end up in terminal scrollback or captured output.
+fn write_token_file(path: &Path, token: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let write = |file: &mut fs::File| {
+        // The mode only applies to new files, an old one may be too open.
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        writeln!(file, "{token}")
+    };
+    options
+        .open(path)
+        .and_then(|mut file| write(&mut file))
+        .map_err(|err| {
+            print_error(format!(
+                "could not write the token to {}: {err}",
+                path.display()
+            ));
+        })
+}
 
-    let address = format!("0.0.0.0:{PORT}");
-    let listener = TcpListener::bind(&address).map_err(|err| {
+fn main() -> Result<()> {
+    set_color("auto")?;
+
+    if let Ok(value) = env::var("TCHAT_SAFE_MODE") {
+        match value.as_str() {
+            "1" | "true" | "on" => SAFE_MODE.store(true, Ordering::Relaxed),
+            "0" | "false" | "off" => SAFE_MODE.store(false, Ordering::Relaxed),
+            _ => print_error(format!(
+                "invalid TCHAT_SAFE_MODE value {value:?}, expected true or false"
+            )),
+        }
+    }
+
+    let mut config = parse_args()?;
+    config.admin_token = env::var("TCHAT_ADMIN_TOKEN")
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty());
+    if let Some(log_file) = &config.log_file {
+        set_log_file(log_file, config.log_max_bytes)?;
+    }
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        (None, None) => None,
+        _ => {
+            print_error("--tls-cert and --tls-key must be given together");
+            return Err(());
+        }
+    };
+
+    let token = match env::var("TCHAT_TOKEN") {
+        Ok(token) if !token.trim().is_empty() => {
+            print_info("using the token from TCHAT_TOKEN");
+            token.trim().to_string()
+        }
+        _ => {
+            let token = random_token();
+            match &config.token_file {
+                Some(path) => {
+                    write_token_file(path, &token)?;
+                    print_info(format!("token written to {}", path.display()));
+                }
+                None => println!("Token: {token}"),
+            }
+            token
+        }
+    };
+
+    let address = SocketAddr::new(config.bind, config.port);
+    let listener = listen(address).map_err(|err| {
         print_error(format!("could not bind {address}: {}", Sens(err)));
     })?;
-    print_info(format!("listening to address: {}", address));
+    print_info(format!(
+        "listening to address: {}{}",
+        address,
+        if tls_config.is_some() { " (TLS)" } else { "" }
+    ));
 
+    let metrics_listener = match config.metrics_port {
+        Some(port) => {
+            let address = SocketAddr::new(config.bind, port);
+            let listener = listen(address).map_err(|err| {
+                print_error(format!("could not bind {address}: {}", Sens(err)));
+            })?;
+            print_info(format!("serving metrics at http://{address}/metrics"));
+            Some(listener)
+        }
+        None => None,
+    };
+
+    let read_buffer = config.read_buffer;
+    let write_timeout = config.write_timeout;
+    let read_timeout = config.read_timeout;
+    let server = Server::new(token, config);
+    if let Some(listener) = metrics_listener {
+        let metrics = server.metrics();
+        thread::spawn(move || metrics::serve(listener, metrics));
+    }
     let (message_sender, message_receiver) = channel();
-    thread::spawn(|| server(message_receiver, token));
+    thread::spawn(move || {
+        let result = server.run(message_receiver);
+        process::exit(if result.is_ok() { 0 } else { 1 });
+    });
+
+    let tick_sender = message_sender.clone();
+    thread::spawn(move || loop {
+        thread::sleep(TICK_INTERVAL);
+        if tick_sender.send(Message::Tick).is_err() {
+            break;
+        }
+    });
+
+    let mut signals = Signals::new([SIGINT, SIGTERM]).map_err(|err| {
+        print_error(format!("could not install signal handler: {err}"));
+    })?;
+    let shutdown_sender = message_sender.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+                print_info(format!("received signal {signal} again, exiting now"));
+                process::exit(1);
+            }
+            print_info("shutting down, press Ctrl-C again to force");
+            let _ = shutdown_sender.send(Message::Shutdown).map_err(|err| {
+                print_error(format!(
+                    "could not send shutdown to the server thread: {err}"
+                ));
+            });
+        }
+    });
 
     for stream in listener.incoming() {
         match stream {
+            Ok(_) if SHUTTING_DOWN.load(Ordering::SeqCst) => {}
             Ok(stream) => {
-                let stream = Arc::new(stream);
-                let message_sender = message_sender.clone();
-                thread::spawn(|| client(stream, message_sender));
+                // IPv4 clients of a dual-stack socket are known by their
+                // plain IPv4 address, like everywhere else.
+                let author_addr = match stream.peer_addr() {
+                    Ok(author_addr) => {
+                        SocketAddr::new(author_addr.ip().to_canonical(), author_addr.port())
+                    }
+                    Err(err) => {
+                        print_error(format!("could not get peer address: {err}"));
+                        continue;
+                    }
+                };
+                // A write that blocks past this fails, and a broadcast drops
+                // the client it failed on.
+                let _ = stream
+                    .set_write_timeout(Some(write_timeout))
+                    .map_err(|err| {
+                        print_warn(format!(
+                            "could not set a write timeout for {}: {err}",
+                            Sens(author_addr)
+                        ));
+                    });
+                // A connection that sends nothing for this long, not even an
+                // answer to a keepalive, ends its reader.
+                let _ = stream.set_read_timeout(Some(read_timeout)).map_err(|err| {
+                    print_warn(format!(
+                        "could not set a read timeout for {}: {err}",
+                        Sens(author_addr)
+                    ));
+                });
+                // Chat lines are small, so don't hold them back to batch them.
+                let _ = stream.set_nodelay(true).map_err(|err| {
+                    print_warn(format!(
+                        "could not disable Nagle's algorithm for {}: {err}",
+                        Sens(author_addr)
+                    ));
+                });
+                let stream: Arc<dyn Connection> = match &tls_config {
+                    Some(tls_config) => match TlsStream::server(stream, tls_config.clone()) {
+                        Ok(stream) => Arc::new(stream),
+                        Err(err) => {
+                            print_error(format!("could not start TLS session: {err}"));
+                            continue;
+                        }
+                    },
+                    None => Arc::new(stream),
+                };
+                if let Ok(Some(stream)) = register(stream, author_addr, &message_sender) {
+                    let message_sender = message_sender.clone();
+                    thread::spawn(move || client(stream, author_addr, read_buffer, message_sender));
+                }
             }
             Err(err) => {
                 print_error(format!("could not accept connection: {err}"));
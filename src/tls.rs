@@ -0,0 +1,206 @@
+use crate::{print_error, Connection, Result};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    result,
+    sync::{Arc, Mutex},
+};
+
+/// A TLS session over a `TcpStream` that one thread can read from while
+/// another writes to it. Only the TLS state is locked; blocking reads on the
+/// socket happen outside the lock.
+pub struct TlsStream {
+    tcp: TcpStream,
+    tls: Mutex<rustls::Connection>,
+}
+
+impl TlsStream {
+    pub fn server(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let tls = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self {
+            tcp,
+            tls: Mutex::new(tls.into()),
+        })
+    }
+
+    pub fn client(tcp: TcpStream, config: Arc<ClientConfig>, host: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        let tls = ClientConnection::new(config, name).map_err(io::Error::other)?;
+        let stream = Self {
+            tcp,
+            tls: Mutex::new(tls.into()),
+        };
+        stream.flush_tls(&mut stream.tls.lock().unwrap())?;
+        Ok(stream)
+    }
+
+    fn flush_tls(&self, tls: &mut rustls::Connection) -> io::Result<()> {
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection for TlsStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.tls.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let mut raw = [0; 4096];
+            let n = Connection::read(&self.tcp, &mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut tls = self.tls.lock().unwrap();
+            let mut received = &raw[..n];
+            while !received.is_empty() {
+                tls.read_tls(&mut received)?;
+                if let Err(err) = tls.process_new_packets() {
+                    // Let the peer know why before giving up on the session.
+                    let _ = self.flush_tls(&mut tls);
+                    return Err(io::Error::new(ErrorKind::InvalidData, err));
+                }
+            }
+            self.flush_tls(&mut tls)?;
+        }
+    }
+
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        io::Write::write_all(&mut tls.writer(), bytes)?;
+        self.flush_tls(&mut tls)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        tls.send_close_notify();
+        let _ = self.flush_tls(&mut tls);
+        self.tcp.shutdown(Shutdown::Both)
+    }
+}
+
+/// Builds the server side configuration from a PEM certificate chain and
+/// private key.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let open = |path: &Path| {
+        File::open(path).map(BufReader::new).map_err(|err| {
+            print_error(format!("could not open {}: {err}", path.display()));
+        })
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|err| {
+            print_error(format!(
+                "could not parse certificates from {}: {err}",
+                cert_path.display()
+            ));
+        })?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|err| {
+            print_error(format!(
+                "could not parse private key from {}: {err}",
+                key_path.display()
+            ));
+        })?
+        .ok_or_else(|| {
+            print_error(format!("no private key found in {}", key_path.display()));
+        })?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| {
+            print_error(format!("invalid TLS certificate or key: {err}"));
+        })?;
+    Ok(Arc::new(config))
+}
+
+/// Builds the client side configuration. Certificates are checked against
+/// the bundled web PKI roots unless `insecure` is set, in which case any
+/// certificate (e.g. a self-signed one) is accepted.
+pub fn client_config(insecure: bool) -> Arc<ClientConfig> {
+    let config = if insecure {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(
+                CryptoProvider::get_default()
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider())),
+            )))
+            .with_no_client_auth()
+    } else {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    };
+    Arc::new(config)
+}
+
+/// Skips certificate validation but still checks that the server owns the
+/// key it presented.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::VecDeque,
+    env, fs,
+    io::{self, Read},
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tchat::{
+    client, listen, Config, Connection, Message, MockClock, Server, DEFAULT_BAN_LIMIT,
+    DEFAULT_MAX_CONNS_PER_IP, DEFAULT_MESSAGE_RATE, DEFAULT_READ_BUFFER, DEFAULT_STRIKE_LIMIT,
+};
+
+struct MockConn {
+    addr: SocketAddr,
+    input: Mutex<VecDeque<Vec<u8>>>,
+    output: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+}
+
+impl MockConn {
+    fn new(addr: &str) -> Arc<Self> {
+        Arc::new(Self {
+            addr: addr.parse().unwrap(),
+            input: Mutex::new(VecDeque::new()),
+            output: Mutex::new(Vec::new()),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Queues `bytes` to come out of a single read.
+    fn feed(&self, bytes: &[u8]) {
+        self.input.lock().unwrap().push_back(bytes.to_vec());
+    }
+
+    fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()).into_owned()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Connection for MockConn {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.input.lock().unwrap();
+        let Some(mut chunk) = input.pop_front() else {
+            return Ok(0);
+        };
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            input.push_front(chunk.split_off(n));
+        }
+        Ok(n)
+    }
+
+    fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        self.output.lock().unwrap().extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+struct Harness {
+    server: Server,
+    clock: MockClock,
+    bans_path: PathBuf,
+}
+
+impl Harness {
+    fn new(name: &str) -> Self {
+        let bans_path = env::temp_dir().join(format!("tchat-{name}-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&bans_path);
+        let clock = MockClock::new(SystemTime::now());
+        let config = Config {
+            bans_path: bans_path.clone(),
+            ..Config::default()
+        };
+        let server = Server::with_clock("TOKEN".to_string(), config, clock.clone());
+        Self {
+            server,
+            clock,
+            bans_path,
+        }
+    }
+
+    fn advance(&self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    fn connect(&mut self, addr: &str) -> Arc<MockConn> {
+        let conn = MockConn::new(addr);
+        let (accepted, _) = channel();
+        self.server.handle_message(Message::ClientConnected {
+            author: conn.clone(),
+            author_addr: conn.addr,
+            accepted,
+        });
+        conn
+    }
+
+    fn send(&mut self, conn: &MockConn, bytes: &[u8]) {
+        self.server.handle_message(Message::NewMessage {
+            author_addr: conn.addr,
+            bytes: bytes.to_vec(),
+        });
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.bans_path);
+    }
+}
+
+fn strike_out(harness: &mut Harness, conn: &MockConn) {
+    for _ in 0..DEFAULT_STRIKE_LIMIT {
+        harness.send(conn, b"\xff\xfe\n");
+    }
+}
+
+#[test]
+fn bans_after_exactly_strike_limit_invalid_messages() {
+    let mut harness = Harness::new("strike-limit");
+    let conn = harness.connect("10.0.0.1:1000");
+
+    for _ in 0..DEFAULT_STRIKE_LIMIT - 1 {
+        harness.send(&conn, b"\xff\xfe\n");
+    }
+    assert!(!harness.server.is_banned(conn.addr.ip()));
+    assert!(!conn.is_closed());
+
+    harness.send(&conn, b"\xff\xfe\n");
+    assert!(harness.server.is_banned(conn.addr.ip()));
+    assert!(conn.is_closed());
+    assert!(conn.output().contains("You are banned MF"));
+}
+
+#[test]
+fn banned_ip_is_rejected_until_ban_limit_elapses() {
+    let mut harness = Harness::new("ban-expiry");
+    let conn = harness.connect("10.0.0.2:1000");
+    strike_out(&mut harness, &conn);
+    harness.server.handle_message(Message::ClientDisconnected {
+        author_addr: conn.addr,
+    });
+
+    harness.advance(DEFAULT_BAN_LIMIT - Duration::from_secs(1));
+    let retry = harness.connect("10.0.0.2:1001");
+    assert!(retry.is_closed());
+    assert!(retry.output().contains("secs left"));
+
+    harness.advance(Duration::from_secs(1));
+    let after = harness.connect("10.0.0.2:1002");
+    assert!(!after.is_closed());
+    assert!(after.output().contains("Please enter the Token:"));
+    assert!(!harness.server.is_banned(after.addr.ip()));
+}
+
+#[test]
+fn ban_expires_exactly_at_ban_limit() {
+    let mut harness = Harness::new("ban-boundary");
+    let conn = harness.connect("10.0.0.3:1000");
+    strike_out(&mut harness, &conn);
+
+    harness.advance(DEFAULT_BAN_LIMIT);
+    assert!(!harness.server.is_banned(conn.addr.ip()));
+    let retry = harness.connect("10.0.0.3:1001");
+    assert!(!retry.is_closed());
+}
+
+#[test]
+fn ban_does_not_affect_other_ips() {
+    let mut harness = Harness::new("ban-other-ip");
+    let conn = harness.connect("10.0.0.4:1000");
+    strike_out(&mut harness, &conn);
+
+    let other = harness.connect("10.0.0.5:1000");
+    assert!(!other.is_closed());
+    assert!(!harness.server.is_banned(other.addr.ip()));
+}
+
+#[test]
+fn ban_holds_across_address_families() {
+    let mut harness = Harness::new("ban-mapped");
+    let conn = harness.connect("10.0.0.8:1000");
+    strike_out(&mut harness, &conn);
+    let mapped = harness.connect("[::ffff:10.0.0.8]:1001");
+    assert!(mapped.is_closed());
+    assert!(mapped.output().contains("secs left"));
+
+    let conn = harness.connect("[::ffff:10.0.0.9]:1000");
+    strike_out(&mut harness, &conn);
+    assert!(harness.server.is_banned("10.0.0.9".parse().unwrap()));
+    let plain = harness.connect("10.0.0.9:1001");
+    assert!(plain.is_closed());
+
+    let v6 = harness.connect("[2001:db8::9]:1000");
+    assert!(!v6.is_closed());
+}
+
+#[test]
+fn stored_bans_match_both_forms_of_an_ipv4_address() {
+    let mut harness = Harness::new("ban-mapped-file");
+    let expiry = SystemTime::now() + DEFAULT_BAN_LIMIT;
+    let secs = expiry.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    fs::write(
+        &harness.bans_path,
+        format!("::ffff:10.0.0.10 {secs}\n10.0.0.11 {secs}\n"),
+    )
+    .unwrap();
+    harness.server = Server::with_clock(
+        "TOKEN".to_string(),
+        Config {
+            bans_path: harness.bans_path.clone(),
+            ..Config::default()
+        },
+        harness.clock.clone(),
+    );
+
+    for ip in [
+        "10.0.0.10",
+        "::ffff:10.0.0.10",
+        "10.0.0.11",
+        "::ffff:10.0.0.11",
+    ] {
+        assert!(harness.server.is_banned(ip.parse().unwrap()), "{ip}");
+    }
+    assert!(harness.connect("10.0.0.10:1000").is_closed());
+    assert!(harness.connect("[::ffff:10.0.0.11]:1000").is_closed());
+}
+
+#[test]
+fn dual_stack_listener_takes_ipv4_and_ipv6_clients() {
+    let listener = listen((Ipv6Addr::UNSPECIFIED, 0).into()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    for ip in ["127.0.0.1", "::1"] {
+        let ip = ip.parse::<IpAddr>().unwrap();
+        let client = TcpStream::connect((ip, port)).unwrap();
+        let (mut accepted, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip().to_canonical(), ip);
+
+        client.write_all(b"hi").unwrap();
+        let mut buffer = [0; 2];
+        accepted.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hi");
+    }
+}
+
+#[test]
+fn connections_beyond_the_per_ip_limit_are_rejected() {
+    let mut harness = Harness::new("per-ip-limit");
+    let conns = (0..DEFAULT_MAX_CONNS_PER_IP)
+        .map(|port| harness.connect(&format!("10.0.0.6:{}", 1000 + port)))
+        .collect::<Vec<_>>();
+    assert!(conns.iter().all(|conn| !conn.is_closed()));
+
+    let extra = harness.connect("10.0.0.6:2000");
+    assert!(extra.is_closed());
+    assert!(extra.output().contains("Too many connections"));
+
+    let other = harness.connect("10.0.0.7:1000");
+    assert!(!other.is_closed());
+}
+
+/// Authenticates `conn` and registers it under `nick`, leaving the rate
+/// limiter ready for the next message.
+fn join(harness: &mut Harness, conn: &MockConn, nick: &str) {
+    harness.send(conn, b"TOKEN\n");
+    harness.advance(DEFAULT_MESSAGE_RATE);
+    harness.send(conn, format!("{nick}\n").as_bytes());
+    harness.advance(DEFAULT_MESSAGE_RATE);
+}
+
+#[test]
+fn messages_faster_than_message_rate_are_held_back() {
+    let mut harness = Harness::new("rate-limit");
+    let alice = harness.connect("10.0.1.1:1000");
+    let bob = harness.connect("10.0.1.2:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    harness.send(&alice, b"first\n");
+    harness.advance(DEFAULT_MESSAGE_RATE / 2);
+    harness.send(&alice, b"too soon\n");
+    assert!(bob.output().contains("<alice> first"));
+    assert!(!bob.output().contains("too soon"));
+
+    harness.advance(DEFAULT_MESSAGE_RATE);
+    harness.server.handle_message(Message::Tick);
+    assert!(bob.output().contains("<alice> too soon"));
+
+    harness.advance(DEFAULT_MESSAGE_RATE);
+    harness.send(&alice, b"later\n");
+    assert!(bob.output().contains("<alice> later"));
+}
+
+#[test]
+fn held_back_messages_beyond_the_queue_drop_the_oldest() {
+    let mut harness = Harness::new("rate-queue");
+    let alice = harness.connect("10.0.1.7:1000");
+    let bob = harness.connect("10.0.1.8:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    for n in 0..=6 {
+        harness.send(&alice, format!("queued {n}\n").as_bytes());
+    }
+    assert!(alice.output().contains("too fast"));
+    for _ in 0..6 {
+        harness.advance(DEFAULT_MESSAGE_RATE);
+        harness.server.handle_message(Message::Tick);
+    }
+    let output = bob.output();
+    assert!(output.contains("<alice> queued 0"));
+    assert!(!output.contains("queued 1"));
+    for n in 2..=6 {
+        assert!(output.contains(&format!("<alice> queued {n}")));
+    }
+    assert!(output.find("queued 2") < output.find("queued 6"));
+}
+
+#[test]
+fn message_burst_is_allowed_then_throttled_to_message_rate() {
+    let mut harness = Harness::new("burst");
+    harness.server = Server::with_clock(
+        "TOKEN".to_string(),
+        Config {
+            bans_path: harness.bans_path.clone(),
+            message_burst: 3,
+            ..Config::default()
+        },
+        harness.clock.clone(),
+    );
+    let alice = harness.connect("10.0.1.3:1000");
+    let bob = harness.connect("10.0.1.4:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    for n in 1..=4 {
+        harness.send(&alice, format!("burst {n}\n").as_bytes());
+    }
+    for n in 1..=3 {
+        assert!(bob.output().contains(&format!("<alice> burst {n}")));
+    }
+    assert!(!bob.output().contains("burst 4"));
+
+    harness.advance(DEFAULT_MESSAGE_RATE);
+    harness.server.handle_message(Message::Tick);
+    harness.send(&alice, b"still throttled\n");
+    assert!(bob.output().contains("<alice> burst 4"));
+    assert!(!bob.output().contains("still throttled"));
+}
+
+#[test]
+fn message_burst_refills_up_to_its_capacity() {
+    let mut harness = Harness::new("burst-refill");
+    harness.server = Server::with_clock(
+        "TOKEN".to_string(),
+        Config {
+            bans_path: harness.bans_path.clone(),
+            message_burst: 2,
+            ..Config::default()
+        },
+        harness.clock.clone(),
+    );
+    let alice = harness.connect("10.0.1.5:1000");
+    let bob = harness.connect("10.0.1.6:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    harness.advance(10 * DEFAULT_MESSAGE_RATE);
+    for n in 1..=3 {
+        harness.send(&alice, format!("quiet {n}\n").as_bytes());
+    }
+    assert!(bob.output().contains("<alice> quiet 2"));
+    assert!(!bob.output().contains("quiet 3"));
+}
+
+#[test]
+fn me_actions_wait_for_and_hold_the_channel_rate() {
+    let mut harness = Harness::new("channel-rate-me");
+    let rate = 10 * DEFAULT_MESSAGE_RATE;
+    harness.server = Server::with_clock(
+        "TOKEN".to_string(),
+        Config {
+            bans_path: harness.bans_path.clone(),
+            channel_rate: Some(rate),
+            ..Config::default()
+        },
+        harness.clock.clone(),
+    );
+    let alice = harness.connect("10.0.1.9:1000");
+    let bob = harness.connect("10.0.1.10:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    harness.send(&alice, b"/me waves\n");
+    harness.send(&bob, b"/me waves back\n");
+    assert!(bob.output().contains("* alice waves"));
+    assert!(!alice.output().contains("waves back"));
+
+    harness.advance(rate);
+    harness.server.handle_message(Message::Tick);
+    assert!(alice.output().contains("* bob waves back"));
+}
+
+#[test]
+fn every_line_of_a_single_read_is_passed_on() {
+    let conn = MockConn::new("10.0.2.1:1000");
+    conn.feed(b"a\nb\nc\n");
+    let (sender, receiver) = channel();
+    let _ = client(conn.clone(), conn.addr, DEFAULT_READ_BUFFER, sender);
+
+    let lines = receiver
+        .try_iter()
+        .filter_map(|message| match message {
+            Message::NewMessage { bytes, .. } => Some(bytes),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lines, [b"a\n".to_vec(), b"b\n".to_vec(), b"c\n".to_vec()]);
+}
+
+#[test]
+fn bytes_are_counted_as_they_are_read() {
+    let mut harness = Harness::new("bytes-in");
+    let conn = MockConn::new("10.0.2.2:1000");
+    let (accepted, accepted_conn) = channel();
+    harness.server.handle_message(Message::ClientConnected {
+        author: conn.clone(),
+        author_addr: conn.addr,
+        accepted,
+    });
+    let reader = accepted_conn.recv().unwrap().unwrap();
+    // Not a whole line yet, so nothing reaches the server thread.
+    conn.feed(b"TOK");
+    let (sender, receiver) = channel();
+    let _ = client(reader, conn.addr, DEFAULT_READ_BUFFER, sender);
+    assert!(!receiver
+        .try_iter()
+        .any(|message| matches!(message, Message::NewMessage { .. })));
+    assert_eq!(harness.server.metrics().bytes_in.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn broadcast_messages_end_with_a_single_newline() {
+    let mut harness = Harness::new("single-newline");
+    let alice = harness.connect("10.0.3.1:1000");
+    let bob = harness.connect("10.0.3.2:1000");
+    join(&mut harness, &alice, "alice");
+    join(&mut harness, &bob, "bob");
+
+    let before = bob.output().len();
+    harness.send(&alice, b"  spaced out  \r\n");
+    let broadcast = bob.output()[before..].to_string();
+    assert!(
+        broadcast.ends_with("<alice>   spaced out  \n"),
+        "{broadcast:?}"
+    );
+    assert_eq!(broadcast.matches('\n').count(), 1);
+}
+
+/// Runs `conn`'s reader to the end and hands everything it read to the
+/// server, like the reader thread would.
+fn read_all(harness: &mut Harness, conn: &Arc<MockConn>) {
+    let (sender, receiver) = channel();
+    let _ = client(conn.clone(), conn.addr, DEFAULT_READ_BUFFER, sender);
+    for message in receiver.try_iter() {
+        if let Message::NewMessage { .. } = message {
+            harness.server.handle_message(message);
+        }
+    }
+}
+
+#[test]
+fn token_sent_before_the_prompt_is_accepted() {
+    let mut harness = Harness::new("early-token");
+    let conn = MockConn::new("10.0.4.1:1000");
+    // Sent right away, before the server got to greet the client.
+    conn.feed(b"TOKEN\n");
+    let (accepted, _) = channel();
+    harness.server.handle_message(Message::ClientConnected {
+        author: conn.clone(),
+        author_addr: conn.addr,
+        accepted,
+    });
+    read_all(&mut harness, &conn);
+
+    let output = conn.output();
+    let prompt = output.find("Please enter the Token:").unwrap();
+    let welcome = output.find("Welcome to the Club!").unwrap();
+    assert!(prompt < welcome, "{output:?}");
+    assert!(!output.contains("Invalid token"), "{output:?}");
+}
+
+#[test]
+fn token_and_nickname_in_one_read_both_count() {
+    let mut harness = Harness::new("early-nick");
+    let alice = harness.connect("10.0.4.2:1000");
+    let bob = harness.connect("10.0.4.3:1000");
+    join(&mut harness, &bob, "bob");
+
+    alice.feed(b"TOKEN\nalice\n");
+    read_all(&mut harness, &alice);
+    // The nickname waits its turn behind the token.
+    harness.advance(DEFAULT_MESSAGE_RATE);
+    harness.server.handle_message(Message::Tick);
+
+    assert!(alice.output().contains("Welcome to the Club!"));
+    assert!(bob.output().contains("alice joined"), "{:?}", bob.output());
+}
+
+#[test]
+fn a_megabyte_without_a_newline_disconnects() {
+    let mut harness = Harness::new("too-long");
+    let conn = harness.connect("10.0.5.1:1000");
+    conn.feed(&vec![b'a'; 1024 * 1024]);
+    let (sender, receiver) = channel();
+    let _ = client(conn.clone(), conn.addr, DEFAULT_READ_BUFFER, sender);
+
+    let too_long = receiver
+        .try_iter()
+        .find(|message| matches!(message, Message::MessageTooLong { .. }))
+        .expect("the reader should give up on the line");
+    harness.server.handle_message(too_long);
+    assert!(conn.is_closed());
+    assert!(
+        conn.output().contains("Message too long!"),
+        "{:?}",
+        conn.output()
+    );
+}